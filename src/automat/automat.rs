@@ -0,0 +1,1094 @@
+//!Представляет собой конечный автомат, содержащий состояние всей игры.
+
+use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+use sender;
+use tasks_queue;
+use timer;
+
+use std::sync::{Arc,Mutex,RwLock};
+use std::collections::VecDeque;
+use std::ops::DerefMut;
+
+use ipc_listener::IpcListenerSender;
+use ipc_listener::IpcListenerCommand;
+use handler::{HandlerSender,HandlerCommand};
+
+use sender::FamiliarityLists;
+
+use super::transitions;
+use super::{Event,StateKind};
+use super::{History,TransitionRecord};
+use super::{Barrier,Phase};
+use super::{AutomatLink,ChannelLink};
+
+use ::ServerType;
+use ::ConnectionID;
+use ::AutomatProperties;
+use ::ArcTasksQueue;
+use ::{ArcTimer,TimerHandle};
+use ::ThreadSource;
+
+const CONNECTED_TO_ALL:usize=1<<ServerType::Storage as usize | 1<<ServerType::Handler as usize;
+
+pub type ArcAutomat=Arc<Automat>;
+
+pub enum AutomatCommand{
+    GenerateMap(String),
+    LoadMap(String),
+    ///Сохраняет текущую карту под именем name, после сохранения игра продолжается
+    SaveMap(String),
+    CloseMap,
+    ///Приостанавливает игру и выполнение задач, соединения и StillAlive сохраняются.
+    ///В рабочих состояниях выполняется без очереди, прерывая текущую стадию до разморозки
+    Freeze,
+    ///Возвращает Автомат в состояние, в котором он был заморожен, выполняется без очереди
+    Defrost,
+    /*
+    //EachSecond,
+    ///Переводит Автомат в Shutdown стадию, если он уже находится в этой стадии, то ничего не происходит.
+    /// * Working->ShutdownHandlers Вызывает shutdown_all для Handler серверов, в случае ошибки отправляет
+    ///Balancer-у BalancerCommand::Error и возвращает ServerTransactionFailed.
+    */
+    Shutdown(bool),
+    ///Отменяет все команды в очереди, текущая операция не прерывается. Выполняется без очереди
+    CancelPending,
+}
+
+pub enum AutomatSignal{
+    Familiarize(Box<FamiliarityLists>),
+    ///Знакомство только с новыми серверами, Автомат остаётся в текущем рабочем состоянии
+    HotJoin(Box<FamiliarityLists>),
+    ConnectedToServers(ServerType),
+    ///Поток готов к стадии, начатой с фазой барьера Phase
+    ThreadIsReady(ThreadSource,Phase),
+    ///Handler получил от Storage все ресурсы загружаемой карты
+    MapLoadedFromStorage,
+    ///Карту невозможно загрузить
+    MapLoadingFailed,
+    ///Граф генерации карты, начатой с фазой барьера Phase, провалился
+    MapGenerationFailed(Phase),
+    ///Сохранение карты, начатое с фазой барьера Phase, провалилось
+    MapSavingFailed(Phase),
+    ///Все сервера подтвердили Goodbye или с ними уже попрощались
+    FarewellFinished,
+    ///Сработал таймаут состояния, устаревшие таймауты игнорируются
+    StateTimeout(TimerHandle),
+}
+
+///Конечный Автомат
+pub struct Automat {
+    inner:Mutex<InnerAutomat>,
+}
+
+///Внутренний Автомат
+pub struct InnerAutomat {
+    properties:AutomatProperties,
+    state:State,
+    link:Box<AutomatLink>,
+    ///Таймаут текущего состояния
+    state_timeout:Option<TimerHandle>,
+    ///Потоки, готовность которых ждёт каждая стадия
+    threads:Barrier<ThreadSource>,
+    commands_queue:VecDeque<AutomatCommand>,
+    ///Типы серверов, с новыми серверами которых Handler ещё знакомится, по одному на каждый HotJoin
+    joining_servers:Vec<ServerType>,
+    ///Перезапустить ли сервер после выключения
+    restart:bool,
+    ///Сигналы стадии, пришедшие во время заморозки, обрабатываются после разморозки
+    deferred_signals:Vec<AutomatSignal>,
+    history:History,
+}
+
+///Состояния Автомата
+#[derive(Clone,Eq,PartialEq,Debug)]
+pub enum State {
+    ///Инициализация
+    Initialization,
+    ///Знакомимся с другими серверами
+    Familiarity(usize),
+    ///Рабочее состояние
+    Working(WorkingState),
+    ///Сервер выключается
+    Shutdown,
+    ///Сервер остановлен
+    Finished
+}
+
+///Состояния Working
+#[derive(Clone,Eq,PartialEq,Debug)]
+pub enum WorkingState {
+    ///Простаивает
+    Nope,
+    ///Генерация карты
+    MapGeneration,
+    ///Загрузка карты: сначала ресурсы получаются от Storage, затем по ним Handler строит карту
+    MapLoading(ServerType),
+    ///Карта создана/загружена
+    MapIsReady,
+    ///Игра
+    Playing,
+    ///Сохранение карты, потоки приостанавливают работу и сбрасывают мир в Storage
+    MapSaving,
+    ///Закрытие карты
+    MapClosing,
+    ///Сервер заморожен, внутри - состояние, в которое он вернётся после разморозки
+    Frozen(Box<WorkingState>),
+}
+
+impl State {
+    ///Состояние без данных, по нему ищутся переходы в таблице переходов
+    pub fn kind(&self) -> StateKind {
+        match *self {
+            State::Initialization => StateKind::Initialization,
+            State::Familiarity(_) => StateKind::Familiarity,
+            State::Working(ref working_state) => {
+                match *working_state {
+                    WorkingState::Nope => StateKind::Nope,
+                    WorkingState::MapGeneration => StateKind::MapGeneration,
+                    WorkingState::MapLoading(ServerType::Storage) => StateKind::MapLoadingFromStorage,
+                    WorkingState::MapLoading(_) => StateKind::MapLoadingByHandler,
+                    WorkingState::MapIsReady => StateKind::MapIsReady,
+                    WorkingState::Playing => StateKind::Playing,
+                    WorkingState::MapSaving => StateKind::MapSaving,
+                    WorkingState::MapClosing => StateKind::MapClosing,
+                    WorkingState::Frozen(_) => StateKind::Frozen,
+                }
+            },
+            State::Shutdown => StateKind::Shutdown,
+            State::Finished => StateKind::Finished,
+        }
+    }
+}
+
+impl std::fmt::Display for State{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self{
+            State::Familiarity(connected_to_servers) => write!(f, "Familiarity({:b})", connected_to_servers),
+            State::Working(WorkingState::Frozen(ref working_state)) =>
+                write!(f, "Frozen({})", State::Working(working_state.as_ref().clone())),
+            _ => write!(f, "{}", self.kind()),
+        }
+    }
+}
+
+impl State {
+    ///Состояние без данных, для замороженного Автомата - состояние, в которое он вернётся
+    pub fn unfrozen_kind(&self) -> StateKind {
+        match *self {
+            State::Working(WorkingState::Frozen(ref working_state)) => State::Working(working_state.as_ref().clone()).kind(),
+            _ => self.kind(),
+        }
+    }
+}
+
+///TransactionError - Ошибка транзакции
+///Poisoned:Mutex сломан(FatalError)
+///BrockenChannel:Канал BalancerSender сломан(FatalError)
+///BalancerCrash:Balancer сломался
+///InvalidTransition:Событие недопустимо в текущем состоянии, Balancer уже оповещён
+
+define_error!( TransactionError,
+    Poisoned() =>
+        "Poisoned",
+    BrockenChannel() =>
+        "Channel for Balancer is broken",
+    BalancerCrash(sender_error:Box<sender::Error>, thread_source:ThreadSource) => //TODO ThreadSource не нужен?
+        "[Source:{2}] Balancer server has crashed: {1}",
+    InvalidTransition(state:State, event:Event) =>
+        "Event {2} is not allowed in state {1}"
+);
+
+impl From<timer::TransactionError> for TransactionError{
+    fn from(timer_error:timer::TransactionError) -> Self{
+        match timer_error {
+            timer::TransactionError::Poisoned(error_info) => TransactionError::Poisoned(error_info),
+            timer::TransactionError::BrockenChannel(error_info) => TransactionError::BrockenChannel(error_info),
+        }
+    }
+}
+
+impl From<tasks_queue::TransactionError> for TransactionError{
+    fn from(tasks_queue_error:tasks_queue::TransactionError) -> Self{
+        match tasks_queue_error {
+            tasks_queue::TransactionError::Poisoned(error_info) => TransactionError::Poisoned(error_info),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! do_automat_transaction {
+    [$operation:expr] => {
+        match $operation {
+            Ok(rv) => rv,
+            Err(automat::TransactionError::Poisoned(error_info)) => return Err(Error::Poisoned(error_info)),
+            Err(automat::TransactionError::BrockenChannel(error_info)) => return Err(Error::BrockenChannel(error_info)),
+            Err(automat::TransactionError::InvalidTransition(_,state,event)) => warn!("Event {} is not allowed in state {}",event,state),
+            _ => {}//TODO
+            //Err(automat::TransactionError::BalancerCrash(error_info,error)) => return Err(Error::BrockenChannel(error_info)),
+        }
+    };
+}
+
+impl Automat {
+    ///Создаёт Автомат, связанный с потоками сервера
+    pub fn new(properties:AutomatProperties, ipc_listener_sender:IpcListenerSender, handler_sender:HandlerSender, tasks_queue:ArcTasksQueue, timer:ArcTimer) -> Self {
+        let link=ChannelLink::new(ipc_listener_sender, handler_sender, tasks_queue, timer);
+
+        Automat::with_link(properties, Box::new(link))
+    }
+
+    ///Создаёт Автомат с произвольной связью, например, в тестах
+    pub fn with_link(properties:AutomatProperties, link:Box<AutomatLink>) -> Self {
+        let mut threads=Barrier::new();
+        threads.register(ThreadSource::IpcListener);
+        threads.register(ThreadSource::Handler);
+
+        let inner=InnerAutomat{
+            properties,
+            state:State::Initialization,
+            link,
+            state_timeout:None,
+            threads,
+            commands_queue:VecDeque::with_capacity(16),
+            joining_servers:Vec::new(),
+            restart:false,
+            deferred_signals:Vec::new(),
+            history:History::new(),
+        };
+
+
+        Automat {
+            inner:Mutex::new(inner)
+        }
+    }
+
+    ///Создаёт ArcAutomat или Arc<Automat>
+    pub fn new_arc(properties:AutomatProperties, ipc_listener_sender:IpcListenerSender, handler_sender:HandlerSender, tasks_queue:ArcTasksQueue, timer:ArcTimer) -> ArcAutomat {
+        Arc::new( Automat::new(properties, ipc_listener_sender, handler_sender, tasks_queue, timer) )
+    }
+
+    pub fn send_command(&self, command:AutomatCommand) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => automat,TransactionError);
+
+        //Замороженный Автомат не дождался бы разморозки, поэтому Shutdown размораживает его,
+        //а все ожидающие команды отменяются
+        match (&automat.state, &command) {
+            (&State::Working(WorkingState::Frozen(_)), &AutomatCommand::Shutdown(..)) => {
+                automat.enqueue(command);
+                automat.process_command(AutomatCommand::Defrost)?;
+
+                if automat.state==State::Working(WorkingState::MapGeneration) {
+                    automat.abort_map_generation(Event::Shutdown)?;
+                    automat.process_next_command()?;
+                }
+
+                return ok!();
+            },
+            _ => {}
+        }
+
+        //Заморозка в рабочем состоянии и разморозка выполняются без очереди, иначе Автомат их не дождётся
+        let bypass_queue=match (&automat.state, &command) {
+            (&State::Working(_), &AutomatCommand::Freeze) |
+            (&State::Working(WorkingState::Frozen(_)), &AutomatCommand::Defrost) |
+            (_, &AutomatCommand::CancelPending) => true,
+            _ => false
+        };
+
+        if automat.is_processing_command() && !bypass_queue {
+            //Генерация карты, которую всё равно закроют, прерывается
+            let abort_map_generation=match (&automat.state, &command) {
+                (&State::Working(WorkingState::MapGeneration), &AutomatCommand::CloseMap) |
+                (&State::Working(WorkingState::MapGeneration), &AutomatCommand::GenerateMap(..)) |
+                (&State::Working(WorkingState::MapGeneration), &AutomatCommand::LoadMap(..)) |
+                (&State::Working(WorkingState::MapGeneration), &AutomatCommand::Shutdown(..)) => true,
+                _ => false
+            };
+
+            if abort_map_generation {
+                automat.abort_map_generation(Event::of_command(&command))?;
+                automat.enqueue(command);
+                automat.process_next_command()?;
+            }else{
+                automat.enqueue(command);
+            }
+        }else{
+            automat.process_command(command)?;
+        }
+
+        ok!()
+    }
+
+    pub fn process_signal(&self, signal:AutomatSignal) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => automat,TransactionError);
+
+        automat.process_signal(signal)
+    }
+
+    pub fn get_state(&self) -> Result<State,TransactionError> {
+        mutex_lock!(&self.inner => automat,TransactionError);
+
+        ok!(automat.state.clone())
+    }
+
+    ///Количество команд, ожидающих в очереди
+    pub fn pending_commands(&self) -> Result<usize,TransactionError> {
+        mutex_lock!(&self.inner => automat,TransactionError);
+
+        ok!(automat.commands_queue.len())
+    }
+
+    ///Количество незавершённых знакомств с новыми серверами
+    pub fn pending_connections(&self) -> Result<usize,TransactionError> {
+        mutex_lock!(&self.inner => automat,TransactionError);
+
+        ok!(automat.joining_servers.len())
+    }
+
+    ///Последние переходы Автомата, от самого старого до самого нового
+    pub fn history(&self) -> Result<Vec<TransitionRecord>,TransactionError> {
+        mutex_lock!(&self.inner => automat,TransactionError);
+
+        ok!(automat.history.records())
+    }
+
+    ///Выводит историю переходов в лог, вызывается при падении потока, поэтому работает и со сломанным Mutex
+    pub fn log_history(&self) {
+        let automat=match self.inner.lock() {
+            Ok(automat) => automat,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        error!("Automat history, state {}:", automat.state);
+
+        for record in automat.history.records() {
+            error!("{}", record);
+        }
+    }
+}
+
+impl InnerAutomat {
+    fn process_command(&mut self, command:AutomatCommand) -> Result<(),TransactionError> {
+        self.check_event(Event::of_command(&command))?;
+
+        match command {
+            AutomatCommand::GenerateMap(map_name) =>
+                self.process_command_generate_map(map_name),
+            AutomatCommand::LoadMap(map_name) =>
+                self.process_command_load_map(map_name),
+            AutomatCommand::SaveMap(map_name) =>
+                self.process_command_save_map(map_name),
+            AutomatCommand::CloseMap =>
+                self.process_command_close_map(),
+            AutomatCommand::Freeze =>
+                self.process_command_freeze(),
+            AutomatCommand::Defrost =>
+                self.process_command_defrost(),
+            AutomatCommand::Shutdown(restart) =>
+                self.process_command_shutdown(restart),
+            AutomatCommand::CancelPending =>
+                self.process_command_cancel_pending(),
+        }
+    }
+
+    ///Ставит команду в очередь, отбрасывая команды, которые она делает бессмысленными:
+    ///* Shutdown отменяет все ожидающие команды, после Shutdown и остановки команды не принимаются
+    ///* GenerateMap, LoadMap и CloseMap закрывают карту, поэтому начиная с первой ожидающей команды,
+    ///открывающей или закрывающей карту, отменяются все команды карты
+    fn enqueue(&mut self, command:AutomatCommand) {
+        let is_shutting_down=match self.state {
+            State::Shutdown | State::Finished => true,
+            _ => self.commands_queue.iter().any(|queued| match *queued {
+                AutomatCommand::Shutdown(..) => true,
+                _ => false
+            })
+        };
+
+        let superseded_from=match command {
+            _ if is_shutting_down => {
+                debug!("Command {} is dropped, server is shutting down", Event::of_command(&command));
+                return;
+            },
+            AutomatCommand::Shutdown(..) => Some(0),
+            AutomatCommand::GenerateMap(..) | AutomatCommand::LoadMap(..) | AutomatCommand::CloseMap => {
+                self.commands_queue.iter().position(|queued| match *queued {
+                    AutomatCommand::GenerateMap(..) | AutomatCommand::LoadMap(..) | AutomatCommand::CloseMap => true,
+                    _ => false
+                })
+            },
+            _ => None
+        };
+
+        match superseded_from {
+            Some(from) => {
+                let is_shutdown=match command {
+                    AutomatCommand::Shutdown(..) => true,
+                    _ => false
+                };
+
+                let superseded=self.commands_queue.split_off(from);
+
+                for queued in superseded {
+                    let is_map_command=match queued {
+                        AutomatCommand::GenerateMap(..) | AutomatCommand::LoadMap(..) |
+                        AutomatCommand::SaveMap(..) | AutomatCommand::CloseMap => true,
+                        _ => false
+                    };
+
+                    if is_shutdown || is_map_command {
+                        debug!("Command {} is superseded by {}", Event::of_command(&queued), Event::of_command(&command));
+                    }else{
+                        self.commands_queue.push_back(queued);
+                    }
+                }
+            },
+            None => {},
+        }
+
+        self.commands_queue.push_back(command);
+    }
+
+    ///Выполняет следующую команду из очереди, команды, недопустимые в текущем состоянии, отклоняются
+    ///и Автомат переходит к следующей. Судьба каждой команды сообщается связи
+    fn process_next_command(&mut self) -> Result<(),TransactionError> {
+        while let Some(command)=self.commands_queue.pop_front() {
+            let event=Event::of_command(&command);
+
+            match self.process_command(command) {
+                Ok(_) => return self.link.command_dequeued(event, true),
+                Err(TransactionError::InvalidTransition(..)) => self.link.command_dequeued(event, false)?,//Handler уже знает об ошибке
+                Err(error) => return Err(error),
+            }
+        }
+
+        ok!()
+    }
+
+    fn process_signal(&mut self, signal:AutomatSignal) -> Result<(),TransactionError> {
+        match signal {
+            AutomatSignal::StateTimeout(handle) if self.state_timeout!=Some(handle) => return ok!(),//Состояние уже сменилось
+            AutomatSignal::ThreadIsReady(_, phase) if !self.threads.is_current(phase) => return ok!(),//Стадия завершена или отменена
+            AutomatSignal::FarewellFinished if self.state==State::Finished => return ok!(),//Подтверждения опоздали к таймауту
+            AutomatSignal::MapGenerationFailed(phase) if !self.threads.is_current(phase) ||
+                self.state.unfrozen_kind()!=StateKind::MapGeneration => return ok!(),//Эта генерация уже прервана
+            AutomatSignal::MapSavingFailed(phase) if !self.threads.is_current(phase) => return ok!(),//Это сохранение уже прервано
+            _ => {}
+        }
+
+        //Стадия замороженного Автомата завершится после разморозки
+        match (&self.state, &signal) {
+            (&State::Working(WorkingState::Frozen(_)), &AutomatSignal::ThreadIsReady(..)) |
+            (&State::Working(WorkingState::Frozen(_)), &AutomatSignal::MapLoadedFromStorage) |
+            (&State::Working(WorkingState::Frozen(_)), &AutomatSignal::MapLoadingFailed) |
+            (&State::Working(WorkingState::Frozen(_)), &AutomatSignal::MapGenerationFailed(_)) |
+            (&State::Working(WorkingState::Frozen(_)), &AutomatSignal::MapSavingFailed(_)) => {
+                debug!("Signal {} is deferred until defrost", Event::of_signal(&signal));
+                self.deferred_signals.push(signal);
+
+                return ok!();
+            },
+            _ => {}
+        }
+
+        self.check_event(Event::of_signal(&signal))?;
+
+        match signal {
+            AutomatSignal::Familiarize(familiarity_lists) => self.process_signal_familiarize(familiarity_lists),
+            AutomatSignal::HotJoin(familiarity_lists) => self.process_signal_hot_join(familiarity_lists),
+            AutomatSignal::ConnectedToServers(server_type) => self.process_signal_connected_to_servers(server_type),
+            AutomatSignal::ThreadIsReady(thread, phase) => self.process_signal_thread_is_ready(thread, phase),
+            AutomatSignal::MapLoadedFromStorage => self.process_signal_map_loaded_from_storage(),
+            AutomatSignal::MapLoadingFailed => self.process_signal_map_loading_failed(),
+            AutomatSignal::MapGenerationFailed(_) => self.process_signal_map_generation_failed(),
+            AutomatSignal::MapSavingFailed(_) => self.process_signal_map_saving_failed(),
+            AutomatSignal::FarewellFinished => self.finish(Event::FarewellFinished),
+            AutomatSignal::StateTimeout(_) => self.process_signal_state_timeout(),
+        }
+    }
+
+    fn is_processing_command(&self) -> bool {
+        match self.state {
+            State::Working(WorkingState::Nope) => false,
+            State::Working(WorkingState::Playing) => false,
+            _ => true
+        }
+    }
+
+    ///Проверяет по таблице переходов, что событие допустимо в текущем состоянии
+    fn check_event(&self, event:Event) -> Result<(),TransactionError> {
+        if transitions::is_event_allowed(self.state.kind(), event) {
+            return ok!();
+        }
+
+        self.invalid_transition(event)
+    }
+
+    ///Переводит Автомат в новое состояние, если переход есть в таблице переходов
+    fn set_state(&mut self, state:State, event:Event) -> Result<(),TransactionError> {
+        if !transitions::is_transition_allowed(self.state.kind(), event, state.kind()) {
+            return self.invalid_transition(event);
+        }
+
+        let record=TransitionRecord{
+            timestamp:std::time::SystemTime::now(),
+            previous:std::mem::replace(&mut self.state, state),
+            event,
+            new:self.state.clone(),
+            queued:self.commands_queue.iter().map(|command| Event::of_command(command)).collect(),
+        };
+
+        if record.previous.kind()!=record.new.kind() {
+            self.set_state_timeout()?;
+        }
+
+        self.history.push(record);
+
+        //Balancer видит состояние каждого Handler-а
+        self.link.send_to_handler(HandlerCommand::StateChanged(self.state.clone(), self.commands_queue.len()))?;
+
+        ok!()
+    }
+
+    ///Отменяет таймаут прежнего состояния и заводит таймаут нового, если он задан
+    fn set_state_timeout(&mut self) -> Result<(),TransactionError> {
+        match self.state_timeout.take() {
+            Some(handle) => self.link.cancel_timeout(handle)?,
+            None => {},
+        }
+
+        let timeout=match self.state.kind() {
+            StateKind::MapGeneration => self.properties.map_generation_timeout,
+            StateKind::MapLoadingFromStorage | StateKind::MapLoadingByHandler => self.properties.map_loading_timeout,
+            StateKind::MapSaving => self.properties.map_saving_timeout,
+            StateKind::MapClosing => self.properties.map_closing_timeout,
+            StateKind::Shutdown => Some(self.properties.goodbye_timeout),
+            _ => None,
+        };
+
+        match timeout {
+            Some(timeout) => self.state_timeout=Some(self.link.schedule_timeout(timeout)?),
+            None => {},
+        }
+
+        ok!()
+    }
+
+    ///Сообщает Handler-у о недопустимом переходе, тот передаст его Balancer-у
+    fn invalid_transition<T>(&self, event:Event) -> Result<T,TransactionError> {
+        self.link.send_to_handler(HandlerCommand::InvalidTransition(self.state.clone(), event))?;
+
+        err!(TransactionError::InvalidTransition, self.state.clone(), event)
+    }
+
+    fn process_command_generate_map(&mut self,map_name:String) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::Nope) => {//Теперь Handler создаст карту
+                debug!("Generating map \"{}\"",map_name);
+                self.set_state(State::Working(WorkingState::MapGeneration), Event::GenerateMap)?;
+                let phase=self.threads.arm();
+
+                self.link.send_to_ipc_listener(IpcListenerCommand::GenerateMap(phase))?;
+                self.link.send_to_handler(HandlerCommand::GenerateMap(map_name, phase))?;
+            },
+            State::Working(WorkingState::MapIsReady) | State::Working(WorkingState::Playing) => {
+                self.commands_queue.push_front(AutomatCommand::GenerateMap(map_name));
+                self.close_map(Event::GenerateMap)?;
+            },
+            _ => return self.invalid_transition(Event::GenerateMap),
+        }
+
+        ok!()
+    }
+
+    fn process_command_close_map(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::Nope) => self.process_next_command()?,
+            State::Working(WorkingState::MapIsReady) | State::Working(WorkingState::Playing) =>
+                self.close_map(Event::CloseMap)?,
+            _ => return self.invalid_transition(Event::CloseMap),
+        }
+
+        ok!()
+    }
+
+    ///Закрывает карту, event - событие, из-за которого карта закрывается
+    fn close_map(&mut self, event:Event) -> Result<(),TransactionError> {
+        debug!("Closing map");
+        self.set_state(State::Working(WorkingState::MapClosing), event)?;
+        self.cancel_map_tasks()?;
+        let phase=self.threads.arm();
+
+        self.link.send_to_ipc_listener(IpcListenerCommand::CloseMap(phase))?;
+        self.link.send_to_handler(HandlerCommand::CloseMap(phase))?;
+
+        ok!()
+    }
+
+    fn process_command_shutdown(&mut self, restart:bool) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Initialization => {
+                self.restart=restart;
+                self.finish(Event::Shutdown)?;
+            },
+            State::Familiarity(_) | State::Working(WorkingState::Nope) => {//Handler прощается с серверами, IpcListener ждёт их ответов
+                self.restart=restart;
+                self.set_state(State::Shutdown, Event::Shutdown)?;
+                self.link.send_to_handler(HandlerCommand::SayGoodbye)?;
+            },
+            State::Working(WorkingState::MapIsReady) | State::Working(WorkingState::Playing) => {
+                self.commands_queue.push_front(AutomatCommand::Shutdown(restart));
+                self.close_map(Event::Shutdown)?;
+            },
+            State::Shutdown | State::Finished => {},
+            _ => return self.invalid_transition(Event::Shutdown),
+        }
+
+        ok!()
+    }
+
+    ///Останавливает потоки, event - Shutdown, FarewellFinished или StateTimeout
+    fn finish(&mut self, event:Event) -> Result<(),TransactionError> {
+        self.set_state(State::Finished, event)?;
+        self.link.send_to_ipc_listener(IpcListenerCommand::Shutdown)?;
+        self.link.send_to_handler(HandlerCommand::Shutdown(self.restart))?;
+
+        ok!()
+    }
+
+    ///Загружает карту: Handler запрашивает ресурсы карты у Storage, затем строит по ним карту
+    fn process_command_load_map(&mut self,map_name:String) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::Nope) => {
+                debug!("Loading map \"{}\"",map_name);
+                self.set_state(State::Working(WorkingState::MapLoading(ServerType::Storage)), Event::LoadMap)?;
+                self.threads.disarm();//Стадию завершает MapLoadedFromStorage, а не готовность потоков
+
+                self.link.send_to_handler(HandlerCommand::LoadMap(map_name))?;
+            },
+            State::Working(WorkingState::MapIsReady) | State::Working(WorkingState::Playing) => {
+                self.commands_queue.push_front(AutomatCommand::LoadMap(map_name));
+                self.close_map(Event::LoadMap)?;
+            },
+            _ => return self.invalid_transition(Event::LoadMap),
+        }
+
+        ok!()
+    }
+
+    fn process_command_save_map(&mut self,map_name:String) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::Nope) => {
+                warn!("There is no map to save as \"{}\"",map_name);
+                self.process_next_command()?;
+            },
+            State::Working(WorkingState::MapIsReady) | State::Working(WorkingState::Playing) => {//Теперь Handler сохранит карту
+                debug!("Saving map as \"{}\"",map_name);
+                self.set_state(State::Working(WorkingState::MapSaving), Event::SaveMap)?;
+                let phase=self.threads.arm();
+
+                self.link.send_to_ipc_listener(IpcListenerCommand::SaveMap(phase))?;
+                self.link.send_to_handler(HandlerCommand::SaveMap(map_name, phase))?;
+            },
+            _ => return self.invalid_transition(Event::SaveMap),
+        }
+
+        ok!()
+    }
+
+    fn process_command_freeze(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::Frozen(_)) => {},
+            State::Working(working_state) => {
+                debug!("Freezing");
+                self.set_state(State::Working(WorkingState::Frozen(Box::new(working_state))), Event::Freeze)?;
+                self.link.pause_tasks()?;
+            },
+            _ => return self.invalid_transition(Event::Freeze),
+        }
+
+        ok!()
+    }
+
+    fn process_command_defrost(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::Frozen(working_state)) => {
+                debug!("Defrosting");
+                self.set_state(State::Working(*working_state), Event::Defrost)?;
+                self.link.resume_tasks()?;
+
+                //Сигналы стадии, пришедшие во время заморозки
+                for signal in std::mem::replace(&mut self.deferred_signals, Vec::new()) {
+                    match self.process_signal(signal) {
+                        Ok(_) | Err(TransactionError::InvalidTransition(..)) => {},
+                        Err(error) => return Err(error),
+                    }
+                }
+
+                //Команды, пришедшие во время заморозки
+                if !self.is_processing_command() {
+                    self.process_next_command()?;
+                }
+            },
+            _ => return self.invalid_transition(Event::Defrost),
+        }
+
+        ok!()
+    }
+
+    fn process_command_cancel_pending(&mut self) -> Result<(),TransactionError> {
+        debug!("{} pending commands have been cancelled", self.commands_queue.len());
+        self.commands_queue.clear();
+
+        ok!()
+    }
+
+    ///Прерывает генерацию карты, которую всё равно закроют, event - команда, из-за которой она прерывается
+    fn abort_map_generation(&mut self, event:Event) -> Result<(),TransactionError> {
+        debug!("Map generation is aborted");
+        self.cancel_map_tasks()?;
+        self.threads.disarm();
+        self.set_state(State::Working(WorkingState::Nope), event)?;
+        self.link.send_to_handler(HandlerCommand::MapGenerationAborted)?;
+
+        ok!()
+    }
+
+    ///Отменяет все задачи текущей карты
+    fn cancel_map_tasks(&mut self) -> Result<(),TransactionError> {
+        let removed=self.link.cancel_map_tasks()?;
+        debug!("Map tasks have been cancelled, {} removed from queue",removed);
+
+        ok!()
+    }
+
+    ///Выполняет знакомство.
+    ///* Если сервер один, то сразу переключает состояние в Working, отправляет Balancer-у FamiliarityFinished
+    ///* Если несколько, то устанавливает состояние в Familiarity, Handler знакомится
+    fn process_signal_familiarize(&mut self, familiarity_lists:Box<FamiliarityLists>) -> Result<(),TransactionError> {
+        let (storages, handlers) = self.link.count_servers(&familiarity_lists);
+
+        self.familiarize(storages, handlers, familiarity_lists)
+    }
+
+    ///Знакомит с storages Storage-ами и handlers Handler-ами из familiarity_lists
+    fn familiarize(&mut self, storages:usize, handlers:usize, familiarity_lists:Box<FamiliarityLists>) -> Result<(),TransactionError> {
+        let mut connected_to_servers=0;
+
+        if storages == 0 { connected_to_servers|=1<<ServerType::Storage as usize; }
+        if handlers == 0 { connected_to_servers|=1<<ServerType::Handler as usize; }
+
+        if connected_to_servers==CONNECTED_TO_ALL {
+            self.set_state(State::Working(WorkingState::Nope), Event::Familiarize)?;
+            self.link.send_to_handler(HandlerCommand::FamiliarityFinished)?;
+            self.process_next_command()?;
+        }else{
+            self.set_state(State::Familiarity(connected_to_servers), Event::Familiarize)?;
+            self.link.send_to_handler(HandlerCommand::Familiarize(familiarity_lists))?;
+        }
+
+        ok!()
+    }
+
+    ///Знакомит Handler с серверами, подключившимися к кластеру после знакомства. Рабочее состояние не меняется,
+    ///Автомат лишь запоминает, знакомства с серверами каких типов ещё не завершены
+    fn process_signal_hot_join(&mut self, familiarity_lists:Box<FamiliarityLists>) -> Result<(),TransactionError> {
+        let mut joining_servers=Vec::with_capacity(2);
+        let (storages, handlers) = self.link.count_servers(&familiarity_lists);
+
+        if storages > 0 { joining_servers.push(ServerType::Storage); }
+        if handlers > 0 { joining_servers.push(ServerType::Handler); }
+
+        if joining_servers.is_empty() {
+            return ok!();
+        }
+
+        debug!("Hot join with {:?}", joining_servers);
+
+        let state=self.state.clone();
+        self.set_state(state, Event::HotJoin)?;
+        self.joining_servers.extend(joining_servers);
+        self.link.send_to_handler(HandlerCommand::HotJoin(familiarity_lists))?;
+
+        ok!()
+    }
+
+    ///Вызывается, когда Handler познакомился с серверами определённого типа, уменьшаем количество серверов(типов), с которыми надо познакомиться,
+    ///Если это число становится равным 0, то переключаемся в состояние Working, при этом отправляется HandlerCommand::FamiliarityFinished.
+    ///В рабочем состоянии так завершается знакомство с новыми серверами
+    fn process_signal_connected_to_servers(&mut self, server_type:ServerType) -> Result<(),TransactionError> {
+        let connected_to_servers=match self.state {
+            State::Familiarity(connected_to_servers) => connected_to_servers | 1<<server_type as usize,
+            State::Working(_) => return self.hot_joined(server_type),
+            _ => return self.invalid_transition(Event::ConnectedToServers),
+        };
+
+        if connected_to_servers==CONNECTED_TO_ALL {
+            self.set_state(State::Working(WorkingState::Nope), Event::ConnectedToServers)?;
+            self.link.send_to_handler(HandlerCommand::FamiliarityFinished)?;
+            self.process_next_command()?;//Команды, пришедшие во время знакомства
+        }else{
+            self.set_state(State::Familiarity(connected_to_servers), Event::ConnectedToServers)?;
+        }
+
+        ok!()
+    }
+
+    ///Handler познакомился с новыми серверами типа server_type
+    fn hot_joined(&mut self, server_type:ServerType) -> Result<(),TransactionError> {
+        let index=match self.joining_servers.iter().position(|&joining| joining==server_type) {
+            Some(index) => index,
+            None => return self.invalid_transition(Event::ConnectedToServers),
+        };
+
+        self.joining_servers.remove(index);
+
+        let state=self.state.clone();
+        self.set_state(state, Event::ConnectedToServers)?;
+
+        if self.joining_servers.is_empty() {
+            debug!("Hot join finished");
+            self.link.send_to_handler(HandlerCommand::HotJoinFinished)?;
+        }
+
+        ok!()
+    }
+
+    ///Сигнализирует, что поток готов, если готовы все потоки, переключаемся на следующую стадию
+    fn process_signal_thread_is_ready(&mut self,thread:ThreadSource,phase:Phase) -> Result<(),TransactionError> {
+        if !self.threads.ready(thread, phase) {
+            return ok!();
+        }
+
+        match self.state.clone() {
+            State::Working(WorkingState::MapGeneration) => {
+                debug!("Map generated");
+                self.set_state(State::Working(WorkingState::MapIsReady), Event::ThreadIsReady)?;
+                self.set_state(State::Working(WorkingState::Playing), Event::Play)?;//TODO
+                self.link.send_to_handler(HandlerCommand::MapGenerated)?;
+                self.process_next_command()?;
+            },
+            State::Working(WorkingState::MapLoading(ServerType::Handler)) => {
+                debug!("Map loaded");
+                self.set_state(State::Working(WorkingState::MapIsReady), Event::ThreadIsReady)?;
+                self.set_state(State::Working(WorkingState::Playing), Event::Play)?;//TODO
+                self.link.send_to_handler(HandlerCommand::MapLoaded)?;
+                self.process_next_command()?;
+            },
+            State::Working(WorkingState::MapSaving) => {
+                debug!("Map saved");
+                self.set_state(State::Working(WorkingState::Playing), Event::ThreadIsReady)?;
+                self.link.send_to_handler(HandlerCommand::MapSaved)?;
+                self.process_next_command()?;
+            },
+            State::Working(WorkingState::MapClosing) => {
+                debug!("Map closed");
+                self.set_state(State::Working(WorkingState::Nope), Event::ThreadIsReady)?;
+                self.link.send_to_handler(HandlerCommand::MapClosed)?;
+                self.process_next_command()?;
+            },
+            _ => return self.invalid_transition(Event::ThreadIsReady),
+        }
+
+        ok!()
+    }
+
+    ///Handler получил все ресурсы карты, теперь потоки строят карту
+    fn process_signal_map_loaded_from_storage(&mut self) -> Result<(),TransactionError> {
+        self.set_state(State::Working(WorkingState::MapLoading(ServerType::Handler)), Event::MapLoadedFromStorage)?;
+        let phase=self.threads.arm();
+
+        self.link.send_to_ipc_listener(IpcListenerCommand::LoadMap(phase))?;
+        self.link.send_to_handler(HandlerCommand::BuildMap(phase))?;
+
+        ok!()
+    }
+
+    ///Потоки не успели подготовиться, Автомат откатывается: карта, которую не удалось создать, загрузить
+    ///или закрыть, сбрасывается(Nope), а после несостоявшегося сохранения игра продолжается(Playing).
+    ///Из MapClosing можно сразу уходить в Nope, хотя отставший поток ещё закрывает карту: команды потокам идут
+    ///по каналам в порядке отправки, поэтому следующую стадию поток начнёт только закрыв карту,
+    ///а его готовность к закрытию придёт со старой фазой и не засчитается новой стадии
+    fn process_signal_state_timeout(&mut self) -> Result<(),TransactionError> {
+        let timed_out_state=self.state.clone();
+        warn!("State {} has timed out, not ready: {:?}", timed_out_state, self.threads.missing());
+
+        match timed_out_state {
+            State::Shutdown => return self.finish(Event::StateTimeout),//Не все сервера подтвердили Goodbye
+            State::Working(WorkingState::MapSaving) =>
+                self.set_state(State::Working(WorkingState::Playing), Event::StateTimeout)?,
+            _ => {
+                self.cancel_map_tasks()?;
+                self.set_state(State::Working(WorkingState::Nope), Event::StateTimeout)?;
+            }
+        }
+
+        self.threads.disarm();
+
+        self.link.send_to_handler(HandlerCommand::StateTimedOut(timed_out_state))?;
+        self.process_next_command()?;
+
+        ok!()
+    }
+
+    ///Карту загрузить не удалось, возвращаемся в Nope
+    fn process_signal_map_loading_failed(&mut self) -> Result<(),TransactionError> {
+        debug!("Map loading failed");
+        self.set_state(State::Working(WorkingState::Nope), Event::MapLoadingFailed)?;
+        self.cancel_map_tasks()?;
+        self.link.send_to_handler(HandlerCommand::MapLoadingFailed)?;
+        self.process_next_command()?;
+
+        ok!()
+    }
+
+    ///Карту сгенерировать не удалось, возвращаемся в Nope
+    fn process_signal_map_generation_failed(&mut self) -> Result<(),TransactionError> {
+        debug!("Map generation failed");
+        self.cancel_map_tasks()?;
+        self.threads.disarm();
+        self.set_state(State::Working(WorkingState::Nope), Event::MapGenerationFailed)?;
+        self.link.send_to_handler(HandlerCommand::MapGenerationFailed)?;
+        self.process_next_command()?;
+
+        ok!()
+    }
+
+    ///Карту сохранить не удалось, игра продолжается с несохранённой картой
+    fn process_signal_map_saving_failed(&mut self) -> Result<(),TransactionError> {
+        debug!("Map saving failed");
+        self.threads.disarm();
+        self.set_state(State::Working(WorkingState::Playing), Event::MapSavingFailed)?;
+        self.link.send_to_handler(HandlerCommand::MapSavingFailed)?;
+        self.process_next_command()?;
+
+        ok!()
+    }
+    /*
+
+    fn process_signal_map_created(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::MapGeneration(ServerType::Storage)) => {//Теперь Handler сгенерирует карту
+                self.state=State::Working(WorkingState::MapGeneration(ServerType::Handler));
+
+                //do_server_transaction![inner.balancer_sender, self.servers.storages.send_all(BalancerToHandler::GenerateMap)];
+            },
+            _ => unreachable!()
+        }
+
+        ok!()
+    }
+
+    fn process_signal_map_generated(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::MapGeneration(server_type)) => {
+                match server_type {
+                    ServerType::Handler => {//Теперь Public переключит игроков на карту
+                        //self.state=State::Working(WorkingState::MapLoading());
+                        self.state=State::Working(WorkingState::Playing);
+                        info!("Map has been generated");
+
+                        let close_map=match self.commands_queue.get(0) {
+                            Some(command) => {
+                                match *command {
+                                    AutomatCommand::CloseMap | AutomatCommand::GenerateMap(..) |
+                                    AutomatCommand::LoadMap(..) | AutomatCommand::Shutdown => true,
+                                    _ => false
+                                }
+                            },
+                            None => false
+                        };
+
+                        if close_map {
+                            self.process_signal_map_closed()?;
+                        }else{
+                            match self.commands_queue.pop_front() {
+                                Some(command) => self.process_command(command)?,
+                                None => {}
+                            }
+
+                            //TODO continue
+                            //do_server_transaction![inner.balancer_sender, self.servers.storages.send_all(BalancerToHandler::Play)];
+                        }
+                    },
+                    _ => unreachable!()
+                }
+            },
+            _ => unreachable!()//TODO if shutdown?
+        }
+
+        ok!()
+    }
+
+    fn process_signal_map_loaded(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::MapLoading(server_type)) => {
+                match server_type {
+                    ServerType::Storage => {
+                        self.state=State::Working(WorkingState::MapLoading(ServerType::Handler));
+                        //do_server_transaction![inner.balancer_sender, self.servers.handlers.send_all(BalancerToHandler::LoadMap)];
+                    },
+                    ServerType::Handler => {
+                        //self.state=State::Working(WorkingState::MapLoading());
+                        self.state=State::Working(WorkingState::Playing);
+                        info!("Map has been loaded");
+
+                        let close_map=match self.commands_queue.get(0) {
+                            Some(command) => {
+                                match *command {
+                                    AutomatCommand::CloseMap | AutomatCommand::GenerateMap(..) |
+                                    AutomatCommand::LoadMap(..) | AutomatCommand::Shutdown => true,
+                                    _ => false
+                                }
+                            },
+                            None => false
+                        };
+
+                        if close_map {
+                            self.process_signal_map_closed()?;
+                        }else{
+                            match self.commands_queue.pop_front() {
+                                Some(command) => self.process_command(command)?,
+                                None => {}
+                            }
+
+                            //TODO continue
+                            //do_server_transaction![inner.balancer_sender, self.servers.storages.send_all(BalancerToHandler::Play)];
+                        }
+                    },
+                    _ => unreachable!()
+                }
+            },
+            _ => unreachable!()
+        }
+
+        ok!()
+    }
+
+    fn process_signal_map_closed(&mut self) -> Result<(),TransactionError> {
+        match self.state.clone() {
+            State::Working(WorkingState::MapClosing(server_type)) => {
+                match server_type {
+                    ServerType::Handler => {
+                        self.state = State::Working(WorkingState::MapLoading(ServerType::Storage));
+                        //do_server_transaction![inner.balancer_sender, self.servers.storages.send_all(BalancerToStorage::CloseMap)];
+                    },
+                    ServerType::Storage => {
+                        self.state = State::Working(WorkingState::Nope);
+                        info!("Map has been closed");
+
+                        match self.commands_queue.pop_front() {
+                            Some(command) => self.process_command(command)?,
+                            None => {}
+                        }
+                    },
+                    _ => unreachable!()
+                }
+            },
+            _ => unreachable!()
+        }
+
+        ok!()
+    }
+    */
+}
@@ -0,0 +1,112 @@
+//!Барьер готовности. Участники регистрируются один раз, затем на каждой стадии барьер взводится заново,
+//!и стадия завершается, когда готовы все зарегистрированные участники.
+//!Каждое взведение начинает новую фазу, готовность приходит с фазой, для которой участник готовился,
+//!поэтому опоздавший ответ прерванной стадии не засчитывается следующей.
+
+use std;
+
+///Фаза барьера, выдаётся при взведении и возвращается вместе с готовностью
+#[derive(Copy,Clone,Eq,PartialEq,Debug)]
+pub struct Phase(u64);
+
+impl Phase {
+    ///Фаза, которую барьер ещё не выдавал или уже сменил
+    #[cfg(test)]
+    pub fn new(phase:u64) -> Self {
+        Phase(phase)
+    }
+}
+
+///Барьер готовности участников P
+pub struct Barrier<P> {
+    participants:Vec<P>,
+    ready:Vec<P>,
+    ///Барьер ждёт участников, после срабатывания он снимается до следующего arm
+    armed:bool,
+    phase:Phase,
+}
+
+impl<P:Clone+Eq+std::fmt::Debug> Barrier<P> {
+    pub fn new() -> Self {
+        Barrier {
+            participants:Vec::new(),
+            ready:Vec::new(),
+            armed:false,
+            phase:Phase(0),
+        }
+    }
+
+    ///Регистрирует участника, повторная регистрация ничего не меняет
+    pub fn register(&mut self, participant:P) {
+        if !self.participants.contains(&participant) {
+            self.participants.push(participant);
+        }
+    }
+
+    ///Удаляет участника, возвращает true, если теперь готовы все оставшиеся участники
+    pub fn unregister(&mut self, participant:&P) -> bool {
+        self.participants.retain(|registered| registered!=participant);
+        self.ready.retain(|ready| ready!=participant);
+
+        self.fire_if_complete()
+    }
+
+    ///Взводит барьер для новой стадии, готовность прошлой стадии сбрасывается.
+    ///Возвращает фазу, которую участники вернут вместе с готовностью
+    pub fn arm(&mut self) -> Phase {
+        self.ready.clear();
+        self.armed=true;
+        self.phase=Phase(self.phase.0+1);
+
+        self.phase
+    }
+
+    ///Снимает барьер, стадия отменена
+    pub fn disarm(&mut self) {
+        self.ready.clear();
+        self.armed=false;
+    }
+
+    ///Отмечает участника готовым, возвращает true, если готовы все участники.
+    ///Готовность незарегистрированного участника, при снятом барьере или к другой фазе игнорируется
+    pub fn ready(&mut self, participant:P, phase:Phase) -> bool {
+        if !self.is_current(phase) {
+            debug!("Participant {:?} is ready for stale {:?}", participant, phase);
+            return false;
+        }
+
+        if !self.participants.contains(&participant) {
+            warn!("Unknown participant {:?} is ready", participant);
+            return false;
+        }
+
+        if !self.ready.contains(&participant) {
+            self.ready.push(participant);
+        }
+
+        self.fire_if_complete()
+    }
+
+    ///Участники, которые ещё не готовы
+    pub fn missing(&self) -> Vec<P> {
+        self.participants.iter().filter(|participant| !self.ready.contains(participant)).cloned().collect()
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    ///Ждёт ли барьер готовности к фазе phase
+    pub fn is_current(&self, phase:Phase) -> bool {
+        self.armed && self.phase==phase
+    }
+
+    fn fire_if_complete(&mut self) -> bool {
+        if self.armed && self.missing().is_empty() {
+            self.armed=false;
+            return true;
+        }
+
+        false
+    }
+}
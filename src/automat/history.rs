@@ -0,0 +1,63 @@
+//!История переходов Автомата. Хранятся только последние HISTORY_CAPACITY переходов,
+//!при падении потока история выводится в лог.
+
+use std;
+
+use std::time::{SystemTime,UNIX_EPOCH};
+use std::collections::VecDeque;
+
+use super::{State,Event};
+
+pub const HISTORY_CAPACITY:usize=64;
+
+///Запись о переходе
+#[derive(Debug,Clone)]
+pub struct TransitionRecord {
+    pub timestamp:SystemTime,
+    pub previous:State,
+    pub event:Event,
+    pub new:State,
+    ///События команд, ожидающих в очереди после перехода
+    pub queued:Vec<Event>,
+}
+
+///Кольцевой буфер последних переходов
+pub struct History {
+    records:VecDeque<TransitionRecord>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            records:VecDeque::with_capacity(HISTORY_CAPACITY),
+        }
+    }
+
+    ///Добавляет запись, самая старая запись вытесняется
+    pub fn push(&mut self, record:TransitionRecord) {
+        if self.records.len()==HISTORY_CAPACITY {
+            self.records.pop_front();
+        }
+
+        self.records.push_back(record);
+    }
+
+    ///Записи от самой старой до самой новой
+    pub fn records(&self) -> Vec<TransitionRecord> {
+        self.records.iter().cloned().collect()
+    }
+}
+
+impl std::fmt::Display for TransitionRecord{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let timestamp=match self.timestamp.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration,
+            Err(_) => std::time::Duration::new(0,0),
+        };
+
+        write!(f, "[{}.{:03}] {} --{}--> {} queued: {:?}",
+            timestamp.as_secs(), timestamp.subsec_nanos()/1_000_000,
+            self.previous, self.event, self.new, self.queued
+        )
+    }
+}
@@ -0,0 +1,105 @@
+//!Связь Автомата с остальным сервером: потоками IpcListener и Handler, очередью задач и таймером.
+//!Автомат не обращается к ним напрямую, поэтому в тестах его можно вести без потоков.
+
+use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+
+use std::time::Duration;
+
+use ipc_listener::{IpcListenerSender,IpcListenerCommand};
+use handler::{HandlerSender,HandlerCommand};
+use sender::FamiliarityLists;
+
+use ::ArcTasksQueue;
+use ::{ArcTimer,TimerEvent,TimerHandle};
+
+use super::TransactionError;
+use super::Event;
+
+///Всё, что Автомат делает за своими пределами
+pub trait AutomatLink: Send {
+    fn send_to_ipc_listener(&self, command:IpcListenerCommand) -> Result<(),TransactionError>;
+    fn send_to_handler(&self, command:HandlerCommand) -> Result<(),TransactionError>;
+
+    ///Команда из очереди выполнена(executed) или отклонена, об отклонении Handler уже получил InvalidTransition
+    fn command_dequeued(&self, event:Event, executed:bool) -> Result<(),TransactionError>;
+
+    ///Количество Storage-ов и Handler-ов, с которыми предстоит познакомиться
+    fn count_servers(&self, familiarity_lists:&FamiliarityLists) -> (usize,usize);
+
+    ///Отменяет задачи текущей карты, возвращает количество задач, удалённых из очереди
+    fn cancel_map_tasks(&self) -> Result<usize,TransactionError>;
+    fn pause_tasks(&self) -> Result<(),TransactionError>;
+    fn resume_tasks(&self) -> Result<(),TransactionError>;
+
+    ///По истечении timeout Автомат получит AutomatSignal::StateTimeout с возвращённым TimerHandle
+    fn schedule_timeout(&self, timeout:Duration) -> Result<TimerHandle,TransactionError>;
+    fn cancel_timeout(&self, handle:TimerHandle) -> Result<(),TransactionError>;
+}
+
+///Связь через каналы потоков, TasksQueue и Timer
+pub struct ChannelLink {
+    ipc_listener_sender:IpcListenerSender,
+    handler_sender:HandlerSender,
+    tasks_queue:ArcTasksQueue,
+    timer:ArcTimer,
+}
+
+impl ChannelLink {
+    pub fn new(ipc_listener_sender:IpcListenerSender, handler_sender:HandlerSender, tasks_queue:ArcTasksQueue, timer:ArcTimer) -> Self {
+        ChannelLink {
+            ipc_listener_sender,
+            handler_sender,
+            tasks_queue,
+            timer,
+        }
+    }
+}
+
+impl AutomatLink for ChannelLink {
+    fn send_to_ipc_listener(&self, command:IpcListenerCommand) -> Result<(),TransactionError> {
+        channel_send!(self.ipc_listener_sender, command, TransactionError);
+
+        ok!()
+    }
+
+    fn send_to_handler(&self, command:HandlerCommand) -> Result<(),TransactionError> {
+        channel_send!(self.handler_sender, command, TransactionError);
+
+        ok!()
+    }
+
+    fn command_dequeued(&self, event:Event, executed:bool) -> Result<(),TransactionError> {
+        if executed {
+            debug!("Queued command {} is executed", event);
+        }else{
+            warn!("Queued command {} is rejected", event);
+        }
+
+        ok!()
+    }
+
+    fn count_servers(&self, familiarity_lists:&FamiliarityLists) -> (usize,usize) {
+        (familiarity_lists.storages.len(), familiarity_lists.handlers.len())
+    }
+
+    fn cancel_map_tasks(&self) -> Result<usize,TransactionError> {
+        ok!(self.tasks_queue.cancel_map_tasks()?)
+    }
+
+    fn pause_tasks(&self) -> Result<(),TransactionError> {
+        ok!(self.tasks_queue.pause()?)
+    }
+
+    fn resume_tasks(&self) -> Result<(),TransactionError> {
+        ok!(self.tasks_queue.resume()?)
+    }
+
+    fn schedule_timeout(&self, timeout:Duration) -> Result<TimerHandle,TransactionError> {
+        ok!(self.timer.schedule_after(timeout, TimerEvent::StateTimeout)?)
+    }
+
+    fn cancel_timeout(&self, handle:TimerHandle) -> Result<(),TransactionError> {
+        ok!(self.timer.cancel(handle)?)
+    }
+}
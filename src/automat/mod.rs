@@ -0,0 +1,18 @@
+pub mod transitions;
+pub use self::transitions::{Event,StateKind,TRANSITIONS};
+
+pub mod history;
+pub use self::history::{History,TransitionRecord};
+
+pub mod barrier;
+pub use self::barrier::{Barrier,Phase};
+
+pub mod link;
+pub use self::link::{AutomatLink,ChannelLink};
+
+#[macro_use]
+pub mod automat;
+pub use self::automat::{Automat,ArcAutomat,InnerAutomat,AutomatCommand,AutomatSignal,State,WorkingState,TransactionError};
+
+#[cfg(test)]
+mod tests;
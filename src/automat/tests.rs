@@ -0,0 +1,798 @@
+//!Автомат ведётся тестовой связью, которая вместо потоков запоминает, какие ответы они пришлют.
+//!Тесты перебирают все короткие и случайные длинные последовательности команд, ответов и таймаутов
+//!и проверяют, что Автомат не паникует, очередь команд всегда исполняется до конца, а Finished конечно.
+
+use std;
+
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+
+use ipc_listener::IpcListenerCommand;
+use handler::HandlerCommand;
+use sender::FamiliarityLists;
+
+use ::AutomatProperties;
+use ::ServerType;
+use ::TimerHandle;
+use ::ThreadSource;
+
+use super::*;
+
+///Ответ, который пришлёт поток
+enum Reply {
+    Ready(ThreadSource,Phase),
+    ///Граф генерации карты выполнен или провалился, отменяется вместе с задачами карты
+    MapGraph(Phase),
+    ///Storage сохранил снимок карты или не смог его сохранить
+    MapSave(Phase),
+    ///Storage прислал ресурсы карты или карту невозможно загрузить
+    MapResources,
+    ///Сервера подтвердили Goodbye
+    Farewell,
+}
+
+struct World {
+    replies:Vec<Reply>,
+    timeouts:Vec<TimerHandle>,
+    next_timeout:usize,
+    tasks_paused:bool,
+    ///Количество Storage-ов и Handler-ов в списках знакомства, сами списки тестам не нужны
+    servers:(usize,usize),
+    ///События, о недопустимости которых Автомат сообщил Handler-у
+    rejected:Vec<Event>,
+    ///Команды, извлечённые из очереди, и были ли они выполнены
+    dequeued:Vec<(Event,bool)>,
+}
+
+struct TestLink {
+    world:Arc<Mutex<World>>,
+}
+
+impl AutomatLink for TestLink {
+    fn send_to_ipc_listener(&self, command:IpcListenerCommand) -> Result<(),TransactionError> {
+        let mut world=self.world.lock().unwrap();
+
+        match command {
+            IpcListenerCommand::GenerateMap(phase) | IpcListenerCommand::LoadMap(phase) |
+            IpcListenerCommand::SaveMap(phase) | IpcListenerCommand::CloseMap(phase) =>
+                world.replies.push(Reply::Ready(ThreadSource::IpcListener, phase)),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn send_to_handler(&self, command:HandlerCommand) -> Result<(),TransactionError> {
+        let mut world=self.world.lock().unwrap();
+
+        match command {
+            HandlerCommand::GenerateMap(_, phase) => world.replies.push(Reply::MapGraph(phase)),
+            HandlerCommand::LoadMap(_) => world.replies.push(Reply::MapResources),
+            HandlerCommand::SaveMap(_, phase) => world.replies.push(Reply::MapSave(phase)),
+            HandlerCommand::BuildMap(phase) | HandlerCommand::CloseMap(phase) =>
+                world.replies.push(Reply::Ready(ThreadSource::Handler, phase)),
+            HandlerCommand::SayGoodbye => world.replies.push(Reply::Farewell),
+            HandlerCommand::InvalidTransition(_, event) => world.rejected.push(event),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn command_dequeued(&self, event:Event, executed:bool) -> Result<(),TransactionError> {
+        self.world.lock().unwrap().dequeued.push((event, executed));
+
+        Ok(())
+    }
+
+    fn count_servers(&self, _familiarity_lists:&FamiliarityLists) -> (usize,usize) {
+        self.world.lock().unwrap().servers
+    }
+
+    fn cancel_map_tasks(&self) -> Result<usize,TransactionError> {
+        let mut world=self.world.lock().unwrap();
+
+        let before=world.replies.len();
+        world.replies.retain(|reply| match *reply {
+            Reply::MapGraph(_) => false,
+            _ => true
+        });
+
+        Ok(before-world.replies.len())
+    }
+
+    fn pause_tasks(&self) -> Result<(),TransactionError> {
+        self.world.lock().unwrap().tasks_paused=true;
+
+        Ok(())
+    }
+
+    fn resume_tasks(&self) -> Result<(),TransactionError> {
+        self.world.lock().unwrap().tasks_paused=false;
+
+        Ok(())
+    }
+
+    fn schedule_timeout(&self, _timeout:Duration) -> Result<TimerHandle,TransactionError> {
+        let mut world=self.world.lock().unwrap();
+
+        let handle=TimerHandle::new(world.next_timeout);
+        world.next_timeout+=1;
+        world.timeouts.push(handle);
+
+        Ok(handle)
+    }
+
+    fn cancel_timeout(&self, handle:TimerHandle) -> Result<(),TransactionError> {
+        self.world.lock().unwrap().timeouts.retain(|&timeout| timeout!=handle);
+
+        Ok(())
+    }
+}
+
+///xorshift, тестам достаточно воспроизводимой последовательности
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0^=self.0 << 13;
+        self.0^=self.0 >> 7;
+        self.0^=self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n:usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+///Шаг теста
+#[derive(Debug,Copy,Clone)]
+enum Action {
+    Command(usize),
+    ///Доставить ответ потока с заданным номером(по модулю количества ответов)
+    Reply(usize),
+    ///Сработать таймауту с заданным номером
+    Timeout(usize),
+    Familiarize,
+}
+
+const COMMANDS:usize=8;
+
+fn command(index:usize) -> AutomatCommand {
+    match index {
+        0 => AutomatCommand::GenerateMap("a".to_string()),
+        1 => AutomatCommand::LoadMap("b".to_string()),
+        2 => AutomatCommand::SaveMap("c".to_string()),
+        3 => AutomatCommand::CloseMap,
+        4 => AutomatCommand::Freeze,
+        5 => AutomatCommand::Defrost,
+        6 => AutomatCommand::CancelPending,
+        _ => AutomatCommand::Shutdown(false),
+    }
+}
+
+struct Harness {
+    automat:Automat,
+    world:Arc<Mutex<World>>,
+    rng:Rng,
+    finished:bool,
+}
+
+impl Harness {
+    fn new(seed:u64) -> Self {
+        let world=Arc::new(Mutex::new(World{
+            replies:Vec::new(),
+            timeouts:Vec::new(),
+            next_timeout:0,
+            tasks_paused:false,
+            servers:(0,0),
+            rejected:Vec::new(),
+            dequeued:Vec::new(),
+        }));
+
+        let timeout=Some(Duration::new(1,0));
+        let properties=AutomatProperties{
+            map_generation_timeout:timeout,
+            map_loading_timeout:timeout,
+            map_saving_timeout:timeout,
+            map_closing_timeout:timeout,
+            goodbye_timeout:Duration::new(1,0),
+        };
+
+        let link=TestLink{
+            world:world.clone(),
+        };
+
+        Harness {
+            automat:Automat::with_link(properties, Box::new(link)),
+            world,
+            rng:Rng(seed | 1),
+            finished:false,
+        }
+    }
+
+    fn state(&self) -> State {
+        match self.automat.get_state() {
+            Ok(state) => state,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    fn pending_commands(&self) -> usize {
+        match self.automat.pending_commands() {
+            Ok(pending_commands) => pending_commands,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    ///Забирает ответы потоков как готовность, не давая графу генерации провалиться
+    fn take_readiness(&self) -> Vec<AutomatSignal> {
+        self.world.lock().unwrap().replies.drain(..).filter_map(|reply| match reply {
+            Reply::Ready(thread, phase) => Some(AutomatSignal::ThreadIsReady(thread, phase)),
+            Reply::MapGraph(phase) | Reply::MapSave(phase) => Some(AutomatSignal::ThreadIsReady(ThreadSource::Handler, phase)),
+            _ => None
+        }).collect()
+    }
+
+    ///Фаза, с которой Handler начал граф генерации карты
+    fn map_graph_phase(&self) -> Phase {
+        let world=self.world.lock().unwrap();
+
+        match world.replies.iter().filter_map(|reply| match *reply {
+            Reply::MapGraph(phase) => Some(phase),
+            _ => None
+        }).next() {
+            Some(phase) => phase,
+            None => panic!("Handler does not generate map"),
+        }
+    }
+
+    ///Знакомит Автомат с заданным количеством Storage-ов и Handler-ов
+    fn familiarize_with(&self, storages:usize, handlers:usize) -> Result<(),TransactionError> {
+        self.world.lock().unwrap().servers=(storages, handlers);
+
+        let familiarity_lists=FamiliarityLists::new(Vec::new(), Vec::new());
+        self.automat.process_signal(AutomatSignal::Familiarize(Box::new(familiarity_lists)))
+    }
+
+    ///Сообщает о горячем подключении заданного количества Storage-ов и Handler-ов
+    fn hot_join(&self, storages:usize, handlers:usize) -> Result<(),TransactionError> {
+        self.world.lock().unwrap().servers=(storages, handlers);
+
+        let familiarity_lists=FamiliarityLists::new(Vec::new(), Vec::new());
+        self.automat.process_signal(AutomatSignal::HotJoin(Box::new(familiarity_lists)))
+    }
+
+    fn history(&self) -> Vec<TransitionRecord> {
+        match self.automat.history() {
+            Ok(history) => history,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    ///Выполняет шаг и проверяет инварианты, возвращает false, если шаг не имел смысла
+    fn step(&mut self, action:Action) -> bool {
+        let result=match action {
+            Action::Command(index) => self.automat.send_command(command(index)),
+            Action::Reply(index) => {
+                let reply={
+                    let mut world=self.world.lock().unwrap();
+
+                    if world.replies.is_empty() {
+                        return false;
+                    }
+
+                    let index=index % world.replies.len();
+                    world.replies.remove(index)
+                };
+
+                let map_loading_fails=self.rng.below(4)==0;
+                let map_generation_fails=self.rng.below(4)==0;
+                let map_saving_fails=self.rng.below(4)==0;
+
+                let signal=match reply {
+                    Reply::Ready(thread, phase) => AutomatSignal::ThreadIsReady(thread, phase),
+                    Reply::MapGraph(phase) if map_generation_fails => AutomatSignal::MapGenerationFailed(phase),
+                    Reply::MapGraph(phase) => AutomatSignal::ThreadIsReady(ThreadSource::Handler, phase),
+                    Reply::MapSave(phase) if map_saving_fails => AutomatSignal::MapSavingFailed(phase),
+                    Reply::MapSave(phase) => AutomatSignal::ThreadIsReady(ThreadSource::Handler, phase),
+                    Reply::MapResources if map_loading_fails => AutomatSignal::MapLoadingFailed,
+                    Reply::MapResources => AutomatSignal::MapLoadedFromStorage,
+                    Reply::Farewell => AutomatSignal::FarewellFinished,
+                };
+
+                self.automat.process_signal(signal)
+            },
+            Action::Timeout(index) => {
+                let handle={
+                    let mut world=self.world.lock().unwrap();
+
+                    if world.timeouts.is_empty() {
+                        return false;
+                    }
+
+                    let index=index % world.timeouts.len();
+                    world.timeouts.remove(index)
+                };
+
+                self.automat.process_signal(AutomatSignal::StateTimeout(handle))
+            },
+            Action::Familiarize => {
+                let familiarity_lists=FamiliarityLists::new(Vec::new(), Vec::new());
+                self.automat.process_signal(AutomatSignal::Familiarize(Box::new(familiarity_lists)))
+            },
+        };
+
+        match result {
+            Ok(_) | Err(TransactionError::InvalidTransition(..)) => {},
+            Err(error) => panic!("{:?}: {}", action, error),
+        }
+
+        self.check_invariants(action);
+
+        true
+    }
+
+    fn check_invariants(&mut self, action:Action) {
+        let state=self.state();
+
+        if self.finished {
+            assert_eq!(state, State::Finished, "{:?} has left Finished", action);
+        }
+
+        self.finished=state==State::Finished;
+
+        for record in self.history() {
+            assert!(
+                transitions::is_transition_allowed(record.previous.kind(), record.event, record.new.kind()),
+                "Transition {} is not in the table", record
+            );
+        }
+
+        match state {
+            State::Working(WorkingState::Frozen(_)) => assert!(self.world.lock().unwrap().tasks_paused),
+            _ => assert!(!self.world.lock().unwrap().tasks_paused, "Tasks are paused in {}", state),
+        }
+
+        //Каждая команда из очереди выполнена или отклонена с InvalidTransition, молча она не теряется
+        let mut world=self.world.lock().unwrap();
+        let mut rejected=std::mem::replace(&mut world.rejected, Vec::new());
+
+        for (event, executed) in world.dequeued.drain(..) {
+            if executed {
+                continue;
+            }
+
+            match rejected.iter().position(|&rejected_event| rejected_event==event) {
+                Some(index) => {rejected.remove(index);},
+                None => panic!("{:?}: queued {} is dropped without InvalidTransition", action, event),
+            }
+        }
+    }
+
+    ///Доводит все стадии до конца: размораживает и доставляет ответы потоков. Таймауты не срабатывают,
+    ///поэтому стадия, потерявшая ответ, останется незавершённой, и каждая команда из очереди должна быть выполнена
+    fn drain(&mut self) {
+        for _ in 0..1000 {
+            let state=self.state();
+
+            match state {
+                State::Working(WorkingState::Frozen(_)) => {
+                    self.step(Action::Command(5));
+                    continue;
+                },
+                _ => {}
+            }
+
+            if self.step(Action::Reply(0)) {
+                continue;
+            }
+
+            let state=self.state();
+
+            assert_eq!(self.pending_commands(), 0, "Commands are stuck in {}", state);
+
+            match state.kind() {
+                StateKind::Nope | StateKind::Playing | StateKind::Finished => {},
+                _ => panic!("Automat is stuck in {}", state),
+            }
+
+            return;
+        }
+
+        panic!("Automat does not settle");
+    }
+}
+
+fn actions() -> Vec<Action> {
+    let mut actions=(0..COMMANDS).map(|index| Action::Command(index)).collect::<Vec<Action>>();
+    actions.push(Action::Reply(0));
+    actions.push(Action::Reply(1));
+    actions.push(Action::Timeout(0));
+
+    actions
+}
+
+#[test]
+fn all_short_sequences_settle() {
+    let actions=actions();
+    let length=4;
+    let count=actions.len().pow(length as u32);
+
+    for mut sequence in 0..count {
+        let mut harness=Harness::new(sequence as u64);
+        harness.step(Action::Familiarize);
+
+        for _ in 0..length {
+            harness.step(actions[sequence % actions.len()]);
+            sequence/=actions.len();
+        }
+
+        harness.drain();
+    }
+}
+
+#[test]
+fn random_sequences_settle() {
+    let actions=actions();
+
+    for seed in 1..300 {
+        let mut harness=Harness::new(seed);
+        let familiarize_at=harness.rng.below(5);
+
+        for step in 0..80 {
+            if step==familiarize_at {
+                harness.step(Action::Familiarize);
+            }
+
+            let action=match actions[harness.rng.below(actions.len())] {
+                Action::Reply(_) => Action::Reply(harness.rng.below(8)),
+                Action::Timeout(_) => Action::Timeout(harness.rng.below(4)),
+                action => action,
+            };
+
+            harness.step(action);
+        }
+
+        harness.drain();
+    }
+}
+
+#[test]
+fn unexpected_signals_do_not_panic() {
+    let mut harness=Harness::new(7);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Familiarize);
+
+    let signals=vec![
+        AutomatSignal::ThreadIsReady(ThreadSource::Handler, Phase::new(1000)),
+        AutomatSignal::ConnectedToServers(ServerType::Storage),
+        AutomatSignal::MapLoadedFromStorage,
+        AutomatSignal::MapLoadingFailed,
+        AutomatSignal::MapGenerationFailed(Phase::new(1000)),
+        AutomatSignal::MapSavingFailed(Phase::new(1000)),
+        AutomatSignal::FarewellFinished,
+        AutomatSignal::StateTimeout(TimerHandle::new(1000)),
+    ];
+
+    for signal in signals {
+        match harness.automat.process_signal(signal) {
+            Ok(_) | Err(TransactionError::InvalidTransition(..)) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    harness.drain();
+}
+
+#[test]
+fn failed_map_generation_rolls_back() {
+    let mut harness=Harness::new(19);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+
+    let phase=harness.map_graph_phase();
+
+    match harness.automat.process_signal(AutomatSignal::MapGenerationFailed(phase)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Nope));
+
+    //Опоздавший ответ IpcListener-а не должен сдвинуть Автомат
+    harness.step(Action::Reply(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::Nope));
+}
+
+#[test]
+fn failure_of_aborted_generation_does_not_abort_next_one() {
+    let mut harness=Harness::new(37);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(0));
+    let stale_phase=harness.map_graph_phase();
+
+    harness.step(Action::Timeout(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::Nope));
+
+    harness.step(Action::Command(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+
+    //Задача графа прерванной генерации провалилась уже во время следующей генерации
+    match harness.automat.process_signal(AutomatSignal::MapGenerationFailed(stale_phase)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+
+    for signal in harness.take_readiness() {
+        match harness.automat.process_signal(signal) {
+            Ok(_) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+}
+
+#[test]
+fn failed_map_saving_returns_to_playing() {
+    let mut harness=Harness::new(41);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(0));
+
+    for signal in harness.take_readiness() {
+        match harness.automat.process_signal(signal) {
+            Ok(_) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    harness.step(Action::Command(2));
+    assert_eq!(harness.state(), State::Working(WorkingState::MapSaving));
+
+    let phase={
+        let world=harness.world.lock().unwrap();
+
+        match world.replies.iter().filter_map(|reply| match *reply {
+            Reply::MapSave(phase) => Some(phase),
+            _ => None
+        }).next() {
+            Some(phase) => phase,
+            None => panic!("Handler does not save map"),
+        }
+    };
+
+    match harness.automat.process_signal(AutomatSignal::MapSavingFailed(phase)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+
+    //Готовность IpcListener-а к несостоявшемуся сохранению уже не засчитывается
+    harness.step(Action::Reply(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+}
+
+#[test]
+fn frozen_stage_finishes_after_defrost() {
+    let mut harness=Harness::new(23);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(0));
+    harness.step(Action::Command(4));
+    assert_eq!(harness.state(), State::Working(WorkingState::Frozen(Box::new(WorkingState::MapGeneration))));
+
+    for signal in harness.take_readiness() {
+        match harness.automat.process_signal(signal) {
+            Ok(_) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    //Стадия завершается только после разморозки, но ответы не теряются
+    assert_eq!(harness.state(), State::Working(WorkingState::Frozen(Box::new(WorkingState::MapGeneration))));
+    harness.step(Action::Command(5));
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+}
+
+#[test]
+fn shutdown_defrosts_frozen_automat() {
+    let mut harness=Harness::new(29);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(4));
+    harness.step(Action::Command(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::Frozen(Box::new(WorkingState::Nope))));
+    assert_eq!(harness.pending_commands(), 1);
+
+    harness.step(Action::Command(7));
+    assert_eq!(harness.state(), State::Shutdown);
+    assert_eq!(harness.pending_commands(), 0);
+}
+
+#[test]
+fn late_readiness_is_not_counted_for_next_stage() {
+    let mut harness=Harness::new(31);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(0));
+    let late=harness.take_readiness();
+    assert_eq!(late.len(), 2);
+
+    harness.step(Action::Timeout(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::Nope));
+
+    harness.step(Action::Command(0));
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+
+    //Готовность к прерванной генерации пришла после таймаута
+    for signal in late {
+        match harness.automat.process_signal(signal) {
+            Ok(_) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+
+    for signal in harness.take_readiness() {
+        match harness.automat.process_signal(signal) {
+            Ok(_) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+}
+
+#[test]
+fn finished_is_terminal() {
+    let mut harness=Harness::new(11);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(7));
+    assert_eq!(harness.state(), State::Shutdown);
+    harness.step(Action::Reply(0));
+    assert_eq!(harness.state(), State::Finished);
+
+    for action in actions() {
+        harness.step(action);
+    }
+
+    harness.step(Action::Familiarize);
+    assert_eq!(harness.state(), State::Finished);
+}
+
+#[test]
+fn dot_has_every_transition() {
+    let mut dot=Vec::new();
+    transitions::write_dot(&mut dot).unwrap();
+    let dot=String::from_utf8(dot).unwrap();
+
+    for &(from,event,to) in TRANSITIONS.iter() {
+        assert!(transitions::STATE_KINDS.contains(&from) && transitions::STATE_KINDS.contains(&to), "{} or {} is not in STATE_KINDS", from, to);
+
+        let edge=dot.lines()
+            .find(|line| line.trim_left().starts_with(&format!("{} -> {} ", from, to)))
+            .expect("Edge is missing");
+        assert!(edge.contains(&event.to_string()), "{} --{}--> {} is missing", from, event, to);
+    }
+}
+
+#[test]
+fn familiarity_waits_for_every_server_type() {
+    let mut harness=Harness::new(37);
+
+    match harness.familiarize_with(2, 1) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state().kind(), StateKind::Familiarity);
+
+    //Команда, пришедшая во время знакомства, выполняется после него
+    harness.step(Action::Command(0));
+    assert_eq!(harness.pending_commands(), 1);
+
+    match harness.automat.process_signal(AutomatSignal::ConnectedToServers(ServerType::Storage)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state().kind(), StateKind::Familiarity);
+
+    match harness.automat.process_signal(AutomatSignal::ConnectedToServers(ServerType::Handler)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+    assert_eq!(harness.pending_commands(), 0);
+
+    harness.drain();
+}
+
+#[test]
+fn hot_join_keeps_working_state() {
+    let mut harness=Harness::new(13);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(0));
+
+    //Пустое горячее подключение ничего не ждёт
+    match harness.hot_join(0, 0) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.automat.pending_connections().unwrap_or(1), 0);
+
+    match harness.hot_join(1, 2) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::MapGeneration));
+    assert_eq!(harness.automat.pending_connections().unwrap_or(0), 2);
+
+    //Генерация завершается, не дожидаясь знакомства с новыми серверами
+    for signal in harness.take_readiness() {
+        match harness.automat.process_signal(signal) {
+            Ok(_) => {},
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    assert!(harness.history().iter().any(|record| record.new==State::Working(WorkingState::MapIsReady)));
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+    assert_eq!(harness.automat.pending_connections().unwrap_or(0), 2);
+
+    match harness.automat.process_signal(AutomatSignal::ConnectedToServers(ServerType::Storage)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+    assert_eq!(harness.automat.pending_connections().unwrap_or(0), 1);
+
+    match harness.automat.process_signal(AutomatSignal::ConnectedToServers(ServerType::Handler)) {
+        Ok(_) => {},
+        Err(error) => panic!("{}", error),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+    assert_eq!(harness.automat.pending_connections().unwrap_or(1), 0);
+
+    match harness.automat.process_signal(AutomatSignal::ConnectedToServers(ServerType::Storage)) {
+        Err(TransactionError::InvalidTransition(..)) => {},
+        _ => panic!("Connection without hot join is accepted"),
+    }
+
+    assert_eq!(harness.state(), State::Working(WorkingState::Playing));
+
+    harness.drain();
+}
+
+#[test]
+fn shutdown_finishes_without_goodbye_acknowledgements() {
+    let mut harness=Harness::new(17);
+
+    harness.step(Action::Familiarize);
+    harness.step(Action::Command(7));
+    assert_eq!(harness.state(), State::Shutdown);
+
+    harness.step(Action::Timeout(0));
+    assert_eq!(harness.state(), State::Finished);
+
+    harness.step(Action::Reply(0));
+    assert_eq!(harness.state(), State::Finished);
+}
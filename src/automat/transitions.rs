@@ -0,0 +1,266 @@
+//!Таблица переходов Автомата. Каждая строка - разрешённый переход: из какого состояния, по какому событию
+//!и в какое состояние. Событие, для которого в текущем состоянии нет ни одной строки, является ошибкой
+//!TransactionError::InvalidTransition, Автомат сообщает о ней Balancer-у, а не паникует.
+
+use std;
+
+use std::io::Write;
+
+use super::{AutomatCommand,AutomatSignal};
+
+///Событие, по которому Автомат меняет состояние
+#[derive(Debug,Copy,Clone,Eq,PartialEq)]
+pub enum Event {
+    //Команды
+    GenerateMap,
+    LoadMap,
+    SaveMap,
+    CloseMap,
+    Freeze,
+    Defrost,
+    Shutdown,
+    CancelPending,
+
+    //Сигналы
+    Familiarize,
+    ///Balancer сообщил о серверах, появившихся после знакомства
+    HotJoin,
+    ConnectedToServers,
+    ThreadIsReady,
+    MapLoadedFromStorage,
+    MapLoadingFailed,
+    ///Задача генерации карты завершилась с ошибкой
+    MapGenerationFailed,
+    ///Storage не сохранил карту
+    MapSavingFailed,
+    ///Все сервера подтвердили Goodbye
+    FarewellFinished,
+    StateTimeout,
+
+    ///Карта готова, начинается игра
+    Play,
+}
+
+///Состояние Автомата без данных, по нему ищутся переходы
+#[derive(Debug,Copy,Clone,Eq,PartialEq)]
+pub enum StateKind {
+    Initialization,
+    Familiarity,
+    Nope,
+    MapGeneration,
+    MapLoadingFromStorage,
+    MapLoadingByHandler,
+    MapIsReady,
+    Playing,
+    MapSaving,
+    MapClosing,
+    Frozen,
+    Shutdown,
+    Finished,
+}
+
+use self::Event as E;
+use self::StateKind as S;
+
+pub const STATE_KINDS:&'static [StateKind]=&[
+    S::Initialization, S::Familiarity, S::Nope, S::MapGeneration, S::MapLoadingFromStorage, S::MapLoadingByHandler,
+    S::MapIsReady, S::Playing, S::MapSaving, S::MapClosing, S::Frozen, S::Shutdown, S::Finished,
+];
+
+///Разрешённые переходы (из состояния, событие, в состояние)
+pub const TRANSITIONS:&'static [(StateKind,Event,StateKind)]=&[
+    //Знакомство
+    (S::Initialization, E::Familiarize, S::Familiarity),
+    (S::Initialization, E::Familiarize, S::Nope),
+    (S::Familiarity, E::ConnectedToServers, S::Familiarity),
+    (S::Familiarity, E::ConnectedToServers, S::Nope),
+
+    //Генерация карты
+    (S::Nope, E::GenerateMap, S::MapGeneration),
+    (S::MapIsReady, E::GenerateMap, S::MapClosing),
+    (S::Playing, E::GenerateMap, S::MapClosing),
+    (S::MapGeneration, E::ThreadIsReady, S::MapGeneration),
+    (S::MapGeneration, E::ThreadIsReady, S::MapIsReady),
+    (S::MapGeneration, E::MapGenerationFailed, S::Nope),
+    //Генерация карты, которую всё равно закроют, прерывается
+    (S::MapGeneration, E::GenerateMap, S::Nope),
+    (S::MapGeneration, E::LoadMap, S::Nope),
+    (S::MapGeneration, E::CloseMap, S::Nope),
+    (S::MapGeneration, E::Shutdown, S::Nope),
+
+    //Загрузка карты
+    (S::Nope, E::LoadMap, S::MapLoadingFromStorage),
+    (S::MapIsReady, E::LoadMap, S::MapClosing),
+    (S::Playing, E::LoadMap, S::MapClosing),
+    (S::MapLoadingFromStorage, E::MapLoadedFromStorage, S::MapLoadingByHandler),
+    (S::MapLoadingFromStorage, E::MapLoadingFailed, S::Nope),
+    (S::MapLoadingByHandler, E::ThreadIsReady, S::MapLoadingByHandler),
+    (S::MapLoadingByHandler, E::ThreadIsReady, S::MapIsReady),
+
+    //Игра
+    (S::MapIsReady, E::Play, S::Playing),
+
+    //Сохранение карты
+    (S::Nope, E::SaveMap, S::Nope),
+    (S::MapIsReady, E::SaveMap, S::MapSaving),
+    (S::Playing, E::SaveMap, S::MapSaving),
+    (S::MapSaving, E::ThreadIsReady, S::MapSaving),
+    (S::MapSaving, E::ThreadIsReady, S::Playing),
+    (S::MapSaving, E::MapSavingFailed, S::Playing),
+
+    //Закрытие карты
+    (S::Nope, E::CloseMap, S::Nope),
+    (S::MapIsReady, E::CloseMap, S::MapClosing),
+    (S::Playing, E::CloseMap, S::MapClosing),
+    (S::MapClosing, E::ThreadIsReady, S::MapClosing),
+    (S::MapClosing, E::ThreadIsReady, S::Nope),
+
+    //Заморозка, после разморозки Автомат возвращается в прежнее состояние
+    (S::Nope, E::Freeze, S::Frozen),
+    (S::MapGeneration, E::Freeze, S::Frozen),
+    (S::MapLoadingFromStorage, E::Freeze, S::Frozen),
+    (S::MapLoadingByHandler, E::Freeze, S::Frozen),
+    (S::MapIsReady, E::Freeze, S::Frozen),
+    (S::Playing, E::Freeze, S::Frozen),
+    (S::MapSaving, E::Freeze, S::Frozen),
+    (S::MapClosing, E::Freeze, S::Frozen),
+    (S::Frozen, E::Freeze, S::Frozen),
+    (S::Frozen, E::Defrost, S::Nope),
+    (S::Frozen, E::Defrost, S::MapGeneration),
+    (S::Frozen, E::Defrost, S::MapLoadingFromStorage),
+    (S::Frozen, E::Defrost, S::MapLoadingByHandler),
+    (S::Frozen, E::Defrost, S::MapIsReady),
+    (S::Frozen, E::Defrost, S::Playing),
+    (S::Frozen, E::Defrost, S::MapSaving),
+    (S::Frozen, E::Defrost, S::MapClosing),
+
+    //Таймауты, Автомат откатывается в безопасное состояние
+    (S::MapGeneration, E::StateTimeout, S::Nope),
+    (S::MapLoadingFromStorage, E::StateTimeout, S::Nope),
+    (S::MapLoadingByHandler, E::StateTimeout, S::Nope),
+    (S::MapSaving, E::StateTimeout, S::Playing),
+    (S::MapClosing, E::StateTimeout, S::Nope),
+    (S::Shutdown, E::StateTimeout, S::Finished),
+
+    //Знакомство с новыми серверами во время работы, состояние не меняется
+    (S::Nope, E::HotJoin, S::Nope),
+    (S::MapGeneration, E::HotJoin, S::MapGeneration),
+    (S::MapLoadingFromStorage, E::HotJoin, S::MapLoadingFromStorage),
+    (S::MapLoadingByHandler, E::HotJoin, S::MapLoadingByHandler),
+    (S::MapIsReady, E::HotJoin, S::MapIsReady),
+    (S::Playing, E::HotJoin, S::Playing),
+    (S::MapSaving, E::HotJoin, S::MapSaving),
+    (S::MapClosing, E::HotJoin, S::MapClosing),
+    (S::Frozen, E::HotJoin, S::Frozen),
+    (S::Nope, E::ConnectedToServers, S::Nope),
+    (S::MapGeneration, E::ConnectedToServers, S::MapGeneration),
+    (S::MapLoadingFromStorage, E::ConnectedToServers, S::MapLoadingFromStorage),
+    (S::MapLoadingByHandler, E::ConnectedToServers, S::MapLoadingByHandler),
+    (S::MapIsReady, E::ConnectedToServers, S::MapIsReady),
+    (S::Playing, E::ConnectedToServers, S::Playing),
+    (S::MapSaving, E::ConnectedToServers, S::MapSaving),
+    (S::MapClosing, E::ConnectedToServers, S::MapClosing),
+    (S::Frozen, E::ConnectedToServers, S::Frozen),
+
+    //Выключение, перед остановкой Handler прощается с серверами
+    (S::Initialization, E::Shutdown, S::Finished),
+    (S::Familiarity, E::Shutdown, S::Shutdown),
+    (S::Nope, E::Shutdown, S::Shutdown),
+    (S::MapIsReady, E::Shutdown, S::MapClosing),
+    (S::Playing, E::Shutdown, S::MapClosing),
+    (S::Shutdown, E::Shutdown, S::Shutdown),
+    (S::Shutdown, E::FarewellFinished, S::Finished),
+    (S::Finished, E::Shutdown, S::Finished),
+];
+
+///События, допустимые в любом состоянии и не меняющие его
+pub const STATELESS_EVENTS:&'static [Event]=&[
+    E::CancelPending,
+];
+
+impl Event {
+    pub fn of_command(command:&AutomatCommand) -> Self {
+        match *command {
+            AutomatCommand::GenerateMap(..) => Event::GenerateMap,
+            AutomatCommand::LoadMap(..) => Event::LoadMap,
+            AutomatCommand::SaveMap(..) => Event::SaveMap,
+            AutomatCommand::CloseMap => Event::CloseMap,
+            AutomatCommand::Freeze => Event::Freeze,
+            AutomatCommand::Defrost => Event::Defrost,
+            AutomatCommand::Shutdown(..) => Event::Shutdown,
+            AutomatCommand::CancelPending => Event::CancelPending,
+        }
+    }
+
+    pub fn of_signal(signal:&AutomatSignal) -> Self {
+        match *signal {
+            AutomatSignal::Familiarize(..) => Event::Familiarize,
+            AutomatSignal::HotJoin(..) => Event::HotJoin,
+            AutomatSignal::ConnectedToServers(..) => Event::ConnectedToServers,
+            AutomatSignal::ThreadIsReady(..) => Event::ThreadIsReady,
+            AutomatSignal::MapLoadedFromStorage => Event::MapLoadedFromStorage,
+            AutomatSignal::MapLoadingFailed => Event::MapLoadingFailed,
+            AutomatSignal::MapGenerationFailed(_) => Event::MapGenerationFailed,
+            AutomatSignal::MapSavingFailed(_) => Event::MapSavingFailed,
+            AutomatSignal::FarewellFinished => Event::FarewellFinished,
+            AutomatSignal::StateTimeout(..) => Event::StateTimeout,
+        }
+    }
+}
+
+///Есть ли в состоянии from переход по событию event
+pub fn is_event_allowed(from:StateKind, event:Event) -> bool {
+    STATELESS_EVENTS.contains(&event) ||
+    TRANSITIONS.iter().any(|&(row_from,row_event,_)| row_from==from && row_event==event)
+}
+
+///Разрешён ли переход из from в to по событию event
+pub fn is_transition_allowed(from:StateKind, event:Event, to:StateKind) -> bool {
+    TRANSITIONS.iter().any(|&(row_from,row_event,row_to)| row_from==from && row_event==event && row_to==to)
+}
+
+///Выводит таблицу переходов в формате Graphviz(DOT), события с одинаковыми концами объединяются в одно ребро
+pub fn write_dot<W:Write>(writer:&mut W) -> std::io::Result<()> {
+    writeln!(writer, "digraph Automat {{")?;
+    writeln!(writer, "    rankdir=LR;")?;
+    writeln!(writer, "    node [shape=box, style=rounded];")?;
+    writeln!(writer, "    {} [shape=box, style=\"rounded,bold\"];", S::Initialization)?;
+    writeln!(writer, "    {} [shape=box, style=\"rounded,filled\"];", S::Finished)?;
+
+    for &state in STATE_KINDS.iter() {
+        let mut targets:Vec<StateKind>=Vec::new();
+
+        for &(from,_,to) in TRANSITIONS.iter() {
+            if from==state && !targets.contains(&to) {
+                targets.push(to);
+            }
+        }
+
+        for &to in targets.iter() {
+            let events=TRANSITIONS.iter()
+                .filter(|&&(row_from,_,row_to)| row_from==state && row_to==to)
+                .map(|&(_,event,_)| event.to_string())
+                .collect::<Vec<String>>();
+
+            writeln!(writer, "    {} -> {} [label=\"{}\"];", state, to, events.join("\\n"))?;
+        }
+    }
+
+    let stateless=STATELESS_EVENTS.iter().map(|event| event.to_string()).collect::<Vec<String>>();
+    writeln!(writer, "    label=\"In any state: {}\";", stateless.join(", "))?;
+    writeln!(writer, "}}")?;
+
+    ok!()
+}
+
+impl std::fmt::Display for Event{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl std::fmt::Display for StateKind{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
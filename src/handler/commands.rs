@@ -2,31 +2,57 @@ use ipc_listener;
 use sender;
 
 use common_messages::MessageConnectionID;
-use automat::{AutomatCommand,AutomatSignal};
+use automat::{AutomatCommand,AutomatSignal,State,Event,Phase};
 use sender::FamiliarityLists;
 
+use task::TaskError;
+use ::TaskOutput;
+use ::{TimerEvent,TimerHandle};
+
 use ::ServerType;
 use ::ServerID;
+use ::ResourceID;
 use ::ConnectionID;
 use ::ThreadSource;
 
 pub enum HandlerCommand {
     IpcListenerThreadCrash(ThreadSource),
     BalancerCrash(ThreadSource),
+    WorkerThreadCrash(usize),
 
     IpcListenerSetupError,
     IpcListenerIsReady,
     ShutdownReceived,
-    Shutdown,
+    ///Завершает поток Handler, true - сервер будет перезапущен
+    Shutdown(bool),
     IpcListenerFinished,
     Task,
 
+    //From workers
+    TaskCompleted(Result<TaskOutput,TaskError>),
+
+    //From TasksQueue
+    TasksQueueOverloaded(usize),
+    TasksQueueRecovered(usize),
+
     //From IPC Listener
     EstablishingConnection,
     AcceptConnection(ServerType,ServerID,ConnectionID,String,ConnectionID),
     ConnectionAccepted(ServerType,ConnectionID,ConnectionID),
     Connected(ServerType,ConnectionID),
-    EachSecond,
+    ///Storage не нашёл запрошенный ресурс
+    ResourceNotFound(ResourceID),
+    ///Storage сохранил ресурс карты
+    ResourceSaved(ResourceID),
+    ///Сервер выключается и прощается с нами
+    #[cfg(feature="farewell")]
+    Goodbye(ServerType,ConnectionID),
+    ///Сервер подтвердил наше Goodbye
+    #[cfg(feature="farewell")]
+    GoodbyeAccepted(ServerType,ConnectionID),
+
+    //From Timer
+    Timer(TimerHandle,TimerEvent),
 
     SenderCommand(SenderCommand),
 
@@ -35,13 +61,35 @@ pub enum HandlerCommand {
     AutomatSignal(AutomatSignal),
 
     //From Automat
+    InvalidTransition(State,Event),
+    StateChanged(State,usize),
+    ///Автомат откатился из состояния по таймауту
+    StateTimedOut(State),
+    MapGenerationAborted,
+    ///Генерация карты провалилась, Автомат вернулся в Nope
+    MapGenerationFailed,
     Familiarize(Box<FamiliarityLists>),
     FamiliarityFinished,
-
-    GenerateMap(String),
+    ///Знакомство с серверами, подключившимися во время работы
+    HotJoin(Box<FamiliarityLists>),
+    HotJoinFinished,
+    ///Отправить Goodbye всем серверам перед выключением
+    SayGoodbye,
+
+    ///Стадии карты, Handler отвечает ThreadIsReady с фазой барьера Автомата
+    GenerateMap(String,Phase),
     MapGenerated,
-    //LoadMap(String),
-    CloseMap,
+    LoadMap(String),
+    BuildMap(Phase),
+    MapLoaded,
+    MapLoadingFailed,
+    SaveMap(String,Phase),
+    ///Задачи остановлены, можно делать снимок карты
+    SnapshotMap(String,Phase),
+    MapSaved,
+    ///Сохранение карты провалилось, Автомат вернулся в Playing
+    MapSavingFailed,
+    CloseMap(Phase),
     MapClosed,
     //Play,
 
@@ -9,6 +9,8 @@ define_error!( Error,
         "[Source:{1}] IpcListener thread has finished incorrecty(crashed)",
     BalancerCrash(thread_source:ThreadSource) =>
         "[Source:{1}] Balancer server has crashed",
+    WorkerThreadCrash(index:usize) =>
+        "Worker #{1} thread has finished incorrecty(crashed)",
     BalancerCrashed(sender_error:Box<sender::Error>) =>
         "Balancer server has crashed: {1}",
 
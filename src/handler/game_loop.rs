@@ -0,0 +1,200 @@
+//!Игровой цикл с фиксированным шагом. В состоянии Playing Timer будит Handler каждый тик, за одно пробуждение
+//!выполняется столько шагов симуляции, сколько накопилось с прошлого пробуждения, но не больше max_catch_up_ticks,
+//!остальное отставание отбрасывается, чтобы Handler не застревал в догонянии.
+
+use std;
+
+use std::time::{Instant,Duration};
+
+use ::GameLoopProperties;
+use ::TimerHandle;
+
+///Статистика тиков с последнего отчёта
+struct TickStats {
+    ticks:u64,
+    ///Тики, которые длились дольше шага
+    overruns:u64,
+    ///Тики, отброшенные из-за ограничения догоняния
+    dropped:u64,
+    total:Duration,
+    longest:Duration,
+}
+
+///Игровой цикл
+pub struct GameLoop {
+    step:Duration,
+    max_catch_up_ticks:usize,
+    ///Событие Timer-а, будящее цикл, None - цикл остановлен
+    timer_handle:Option<TimerHandle>,
+    ///Момент, до которого симуляция уже рассчитана
+    simulated_until:Instant,
+    tick:u64,
+    stats:TickStats,
+}
+
+impl TickStats {
+    fn new() -> Self {
+        TickStats {
+            ticks:0,
+            overruns:0,
+            dropped:0,
+            total:Duration::new(0,0),
+            longest:Duration::new(0,0),
+        }
+    }
+}
+
+impl GameLoop {
+    pub fn new(properties:&GameLoopProperties) -> Self {
+        GameLoop {
+            step:Duration::new(0, 1_000_000_000/properties.tick_rate),
+            max_catch_up_ticks:properties.max_catch_up_ticks,
+            timer_handle:None,
+            simulated_until:Instant::now(),
+            tick:0,
+            stats:TickStats::new(),
+        }
+    }
+
+    ///Длительность одного тика
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.timer_handle.is_some()
+    }
+
+    ///Будит ли цикл событие handle, после остановки Timer может прислать ещё несколько тиков
+    pub fn is_woken_by(&self, handle:TimerHandle) -> bool {
+        self.timer_handle==Some(handle)
+    }
+
+    ///Запускает цикл, timer_handle - событие Timer-а, повторяющееся каждый шаг
+    pub fn start(&mut self, timer_handle:TimerHandle) {
+        info!("Game loop is started, {:?} per tick", self.step);
+
+        self.timer_handle=Some(timer_handle);
+        self.simulated_until=Instant::now();
+        self.stats=TickStats::new();
+    }
+
+    ///Останавливает цикл, возвращает событие Timer-а, которое нужно отменить
+    pub fn stop(&mut self) -> Option<TimerHandle> {
+        if self.is_running() {
+            self.report();
+            info!("Game loop is stopped at tick {}", self.tick);
+        }
+
+        self.timer_handle.take()
+    }
+
+    ///Количество тиков, которые нужно выполнить сейчас
+    pub fn due_ticks(&mut self, now:Instant) -> usize {
+        if now <= self.simulated_until {
+            return 0;
+        }
+
+        let behind=nanos(now-self.simulated_until);
+        let due=(behind/nanos(self.step)) as usize;
+
+        //Симуляция сдвигается на все накопившиеся тики, даже отброшенные
+        self.simulated_until+=self.step*due as u32;
+
+        if due > self.max_catch_up_ticks {
+            let dropped=due-self.max_catch_up_ticks;
+            warn!("Game loop is {} ticks behind, {} ticks are dropped", due, dropped);
+            self.stats.dropped+=dropped as u64;
+
+            return self.max_catch_up_ticks;
+        }
+
+        due
+    }
+
+    ///Учитывает выполненный тик
+    pub fn record_tick(&mut self, duration:Duration) {
+        self.tick+=1;
+        trace!("Tick {} took {:?}", self.tick, duration);
+
+        self.stats.ticks+=1;
+        self.stats.total+=duration;
+
+        if duration > self.stats.longest {
+            self.stats.longest=duration;
+        }
+
+        if duration > self.step {
+            self.stats.overruns+=1;
+        }
+    }
+
+    ///Выводит в лог статистику с прошлого отчёта и сбрасывает её
+    pub fn report(&mut self) {
+        if !self.is_running() {
+            return;
+        }
+
+        let stats=std::mem::replace(&mut self.stats, TickStats::new());
+
+        let average=if stats.ticks==0 {
+            Duration::new(0,0)
+        }else{
+            stats.total/stats.ticks as u32
+        };
+
+        debug!("Game loop: {} ticks, average {:?}, longest {:?}, {} overruns, {} dropped",
+            stats.ticks, average, stats.longest, stats.overruns, stats.dropped
+        );
+    }
+}
+
+fn nanos(duration:Duration) -> u64 {
+    duration.as_secs()*1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Цикл с шагом 100мс
+    fn game_loop(max_catch_up_ticks:usize) -> GameLoop {
+        GameLoop::new(&GameLoopProperties{
+            tick_rate:10,
+            max_catch_up_ticks,
+        })
+    }
+
+    #[test]
+    fn no_ticks_before_step() {
+        let mut game_loop=game_loop(5);
+        let start=game_loop.simulated_until;
+
+        assert_eq!(game_loop.due_ticks(start), 0);
+        assert_eq!(game_loop.due_ticks(start+Duration::from_millis(99)), 0);
+        assert_eq!(game_loop.simulated_until, start);
+    }
+
+    #[test]
+    fn remainder_of_step_is_kept() {
+        let mut game_loop=game_loop(5);
+        let start=game_loop.simulated_until;
+
+        assert_eq!(game_loop.due_ticks(start+Duration::from_millis(350)), 3);
+        assert_eq!(game_loop.simulated_until, start+Duration::from_millis(300));
+        assert_eq!(game_loop.due_ticks(start+Duration::from_millis(400)), 1);
+    }
+
+    #[test]
+    fn lag_over_limit_is_dropped() {
+        let mut game_loop=game_loop(5);
+        let start=game_loop.simulated_until;
+
+        assert_eq!(game_loop.due_ticks(start+Duration::from_millis(10_000)), 5);
+        assert_eq!(game_loop.stats.dropped, 95);
+
+        //Отброшенные тики не догоняются позже
+        assert_eq!(game_loop.simulated_until, start+Duration::from_millis(10_000));
+        assert_eq!(game_loop.due_ticks(start+Duration::from_millis(10_050)), 0);
+    }
+}
@@ -3,29 +3,42 @@ use nes::{ErrorInfo,ErrorInfoTrait};
 use sender;
 use nanomsg;
 use automat;
+use tasks_queue;
+use timer;
 use ipc_listener;
 
 use std::io::Write;
 use std::thread::JoinHandle;
-use std::collections::HashSet;
+use std::time::{Duration,Instant};
+use std::collections::{HashSet,HashMap};
 
 use ipc_listener::{IpcListenerSender, IpcListenerCommand};
 
 use sender::SenderTrait;
-use automat::{AutomatCommand,AutomatSignal};
+use automat::{AutomatCommand,AutomatSignal,State,WorkingState,Phase};
 
 use ::ArcProperties;
-use ::{TasksQueue, ArcTasksQueue};
+use task::{TaskEntry,TaskError,MapGenerationStep};
+use ::{Task, ControlTask, TaskOutput, TasksQueue, ArcTasksQueue, TaskGraph};
+use ::WorkerPool;
+use ::{Journal, MapStage, Recovery, RecoveredStage, RecoveredNode};
+use ::{Timer, ArcTimer, TimerEvent, TimerHandle};
 use ::{Sender, ArcSender};
 use ::{Automat, ArcAutomat};
 use ::ThreadSource;
 use ::ServerType;
 use ::ConnectionID;
+use ::ServerID;
+use ::ResourceID;
 
 use super::Error;
 use super::{HandlerCommand,SenderCommand};
+use super::Map;
+use super::GameLoop;
 
-use common_messages::{HandlerToBalancer};
+use common_messages::{HandlerToBalancer,HandlerToStorage};
+#[cfg(feature="farewell")]
+use common_messages::HandlerToHandler;
 use common_messages::MessageConnectionID;
 
 pub type HandlerSender = std::sync::mpsc::Sender<HandlerCommand>;
@@ -33,10 +46,45 @@ pub type HandlerReceiver = std::sync::mpsc::Receiver<HandlerCommand>;
 
 pub struct Handler {
     handler_receiver:HandlerReceiver,
+    ///Handler отправляет команды и самому себе, чтобы они выполнились после уже пришедших
+    handler_sender:HandlerSender,
     ipc_listener_sender:IpcListenerSender,
     tasks_queue:ArcTasksQueue,
+    worker_pool:WorkerPool,
+    timer:ArcTimer,
     sender:ArcSender,
     automat:ArcAutomat,
+    map:Option<Map>,
+    ///Storage-сервера, с которыми установлено соединение
+    storages:Vec<ConnectionID>,
+    ///Storage-сервера, ServerID которых известен, только они хранят ресурсы карт
+    storage_servers:Vec<(ServerID,ConnectionID)>,
+    ///ServerID серверов, подключающихся к нам, по номеру их соединения с Balancer-ом, до установки соединения
+    server_ids:HashMap<ConnectionID,ServerID>,
+    ///Handler-ы, с которыми установлено соединение
+    handlers:Vec<ConnectionID>,
+    ///Сервера, которые ещё не подтвердили наше Goodbye
+    farewell:Vec<(ServerType,ConnectionID)>,
+    game_loop:GameLoop,
+    ///Шаги генерации карты должны уложиться в таймаут стадии
+    map_generation_timeout:Option<Duration>,
+    ///Номер текущей стадии карты в журнале
+    stage:Option<u64>,
+    ///Стадия, прерванная падением, Handler повторяет её после знакомства
+    recovered_stage:Option<RecoveredStage>,
+    ///Ответы Storage из журнала, они возвращаются в очередь, когда восстановленная стадия снова создаст карту
+    recovered_tasks:Vec<(u64,Task)>,
+    ///Сохраняемый снимок карты, ждёт подтверждения своих ресурсов от Storage
+    saving:Option<SavingSnapshot>,
+}
+
+///Снимок карты, ресурсы которого отправлены в Storage на сохранение
+struct SavingSnapshot {
+    snapshot:Map,
+    phase:Phase,
+    connection_id:ConnectionID,
+    ///Ресурсы, сохранение которых Storage ещё не подтвердил
+    unsaved:Vec<ResourceID>,
 }
 
 macro_rules! do_sender_transaction {
@@ -59,13 +107,37 @@ macro_rules! do_sender_transaction {
 }
 
 impl Handler {
-    pub fn start(ipc_listener_sender:IpcListenerSender, properties: ArcProperties) -> JoinHandle<()>{
+    ///Поток Handler возвращает true, если сервер должен быть перезапущен
+    pub fn start(ipc_listener_sender:IpcListenerSender, properties: ArcProperties) -> JoinHandle<bool>{
         let (handler_sender, handler_receiver) = std::sync::mpsc::channel();
 
         let join_handle=std::thread::Builder::new().name("Handler.Handler".to_string()).spawn(move|| {
             try_send![ipc_listener_sender, IpcListenerCommand::HandlerSender(handler_sender.clone())];
 
-            let tasks_queue = TasksQueue::new_arc();
+            let (journal, recovery) = match Journal::open(&properties.journal) {
+                Ok(journal_and_recovery) => journal_and_recovery,
+                Err(error) => {
+                    error!("Journal error: {}, tasks will not be journaled", error);
+                    (None, Recovery{tasks:Vec::new(), stage:None})
+                }
+            };
+
+            let tasks_queue = TasksQueue::new_arc(&properties.tasks_queue, properties.workers.count, handler_sender.clone(), journal);
+
+            let Recovery{tasks:mut recovered_tasks, stage:recovered_stage}=recovery;
+
+            //Без незавершённой стадии ответы Storage не к чему применить, они только отмечаются выполненными
+            if recovered_stage.is_none() {
+                if recovered_tasks.len() > 0 {
+                    info!("Replaying {} tasks from journal", recovered_tasks.len());
+                }
+
+                for (journal_id, task) in recovered_tasks.drain(..) {
+                    if tasks_queue.replay(journal_id, task).is_err() {
+                        panic!("Can not replay journal");
+                    }
+                }
+            }
 
             try_send![ipc_listener_sender, IpcListenerCommand::TasksQueue(tasks_queue.clone())];
 
@@ -82,24 +154,34 @@ impl Handler {
 
                     try_send![ipc_listener_sender, IpcListenerCommand::SenderCreationError];
 
-                    return;
+                    return false;
                 }
             };
 
             try_send![ipc_listener_sender, IpcListenerCommand::Sender(sender.clone())];
 
-            let automat=Automat::new_arc(properties.clone(), ipc_listener_sender.clone(), handler_sender.clone());
+            let timer = Timer::new_arc(handler_sender.clone());
+            let automat=Automat::new_arc(properties.automat.clone(), ipc_listener_sender.clone(), handler_sender.clone(), tasks_queue.clone(), timer.clone());
 
             if ipc_listener_sender.send(IpcListenerCommand::Automat(automat.clone())).is_err() {
                 panic!("Can not send Automat");
             }
 
+            let worker_pool = WorkerPool::start(properties.workers.count, tasks_queue.clone(), handler_sender.clone());
+
             let mut handler = match Handler::setup(
                 handler_receiver,
+                handler_sender.clone(),
                 ipc_listener_sender.clone(),
                 tasks_queue,
+                worker_pool,
+                timer,
                 sender,
-                automat
+                automat,
+                GameLoop::new(&properties.game_loop),
+                properties.automat.map_generation_timeout,
+                recovered_stage,
+                recovered_tasks
             ) {
                 Ok( handler ) => handler,
                 Err( error ) => {
@@ -107,24 +189,30 @@ impl Handler {
 
                     try_send![ipc_listener_sender, IpcListenerCommand::HandlerSetupError(Box::new(error))];
 
-                    return;
+                    return false;
                 }
             };
 
             handler.synchronize_setup();
 
             match handler.lifecycle() {
-                Ok(_) => {
+                Ok(restart) => {
                     //do something
 
                     handler.synchronize_finish();
+
+                    restart
                 }
                 Err(error) => {
                     error!("Handler Error: {}", error);
+                    handler.automat.log_history();
 
                     match error {
                         Error::IpcListenerThreadCrash(_,source) => {
-                            //TODO:try to save world
+                            //Мир восстанавливается из журнала после перезапуска, поэтому журнал нужно только сбросить на диск
+                            if let Err(error)=handler.tasks_queue.sync_journal() {
+                                error!("Can not sync journal: {}", error);
+                            }
                         },
                         Error::BalancerCrash(_,source) => {},
                         Error::BalancerCrashed(_,e) => {
@@ -136,6 +224,8 @@ impl Handler {
                             try_send![handler.ipc_listener_sender, IpcListenerCommand::HandlerThreadCrash(ThreadSource::Handler)];
                         }
                     }
+
+                    false
                 }
             }
         }).unwrap();
@@ -145,17 +235,39 @@ impl Handler {
 
     fn setup(
         handler_receiver:HandlerReceiver,
+        handler_sender:HandlerSender,
         ipc_listener_sender:IpcListenerSender,
         tasks_queue:ArcTasksQueue,
+        worker_pool:WorkerPool,
+        timer:ArcTimer,
         sender:ArcSender,
-        automat:ArcAutomat
+        automat:ArcAutomat,
+        game_loop:GameLoop,
+        map_generation_timeout:Option<Duration>,
+        recovered_stage:Option<RecoveredStage>,
+        recovered_tasks:Vec<(u64,Task)>
     ) -> Result<Self,Error> {
         let handler = Handler{
             handler_receiver,
+            handler_sender,
             ipc_listener_sender,
             tasks_queue,
+            worker_pool,
+            timer,
             sender,
-            automat
+            automat,
+            map:None,
+            storages:Vec::new(),
+            storage_servers:Vec::new(),
+            server_ids:HashMap::new(),
+            handlers:Vec::new(),
+            farewell:Vec::new(),
+            game_loop,
+            map_generation_timeout,
+            stage:None,
+            recovered_stage,
+            recovered_tasks,
+            saving:None,
         };
 
         ok!( handler )
@@ -172,22 +284,34 @@ impl Handler {
         try_send![self.ipc_listener_sender, IpcListenerCommand::HandlerIsReady];
     }
 
-    fn lifecycle(&mut self) -> Result<(),Error> {
+    ///Возвращает флаг перезапуска из Shutdown
+    fn lifecycle(&mut self) -> Result<bool,Error> {
         ///Отвечаем Balancer-у
         try!(self.sender.balancer_sender.send(&HandlerToBalancer::ServerStarted), Error::BalancerCrashed);
 
-        self.lifecycle_handle()?;
+        do_timer_transaction!(self.timer.schedule_every(Duration::new(1,0), TimerEvent::EachSecond));
+
+        let restart=self.lifecycle_handle()?;
+
+        //Стадия, прерванная выключением с перезапуском, будет повторена после перезапуска
+        if !restart {
+            self.complete_stage()?;
+        }
+
         self.lifecycle_shutdown()?;
 
-        ok!()
+        ok!(restart)
     }
 
-    fn lifecycle_handle(&mut self) -> Result<(),Error> {
+    fn lifecycle_handle(&mut self) -> Result<bool,Error> {
         use std::time::SystemTime;
         use std::io::Read;
 
+        //Without workers tasks are executed by this thread
+        let inline_tasks=self.worker_pool.is_empty();
+
         loop {
-            let mut wait_tasks=!self.tasks_queue.is_task();
+            let mut wait_tasks=!inline_tasks || !do_tasks_queue_transaction!(self.tasks_queue.is_task());
 
             //recv all commands from channel, blocking wait if no tasks
             loop {
@@ -212,59 +336,816 @@ impl Handler {
                 match command {
                     HandlerCommand::IpcListenerThreadCrash(source) => return err!(Error::IpcListenerThreadCrash, source),
                     HandlerCommand::BalancerCrash(source) => return err!(Error::BalancerCrash, source),
+                    HandlerCommand::WorkerThreadCrash(index) => return err!(Error::WorkerThreadCrash, index),
                     HandlerCommand::AutomatSignal(signal) => do_automat_transaction!(self.automat.process_signal(signal)),
                     HandlerCommand::AutomatCommand(command) => do_automat_transaction!(self.automat.send_command(command)),
-                    HandlerCommand::Shutdown => return ok!(),
+                    HandlerCommand::Shutdown(restart) => return ok!(restart),
                     HandlerCommand::Task => {
-                        wait_tasks=false;
+                        if inline_tasks {
+                            wait_tasks=false;
+                        }
+                    },
+                    HandlerCommand::TaskCompleted(result) =>
+                        self.handle_task_result(result)?,
+                    HandlerCommand::TasksQueueOverloaded(tasks_count) => {
+                        warn!("TasksQueue is overloaded: {} tasks", tasks_count);
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::Overloaded(tasks_count as u32)), Error::BalancerCrashed);
+                    },
+                    HandlerCommand::TasksQueueRecovered(tasks_count) => {
+                        info!("TasksQueue has recovered: {} tasks", tasks_count);
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::Recovered(tasks_count as u32)), Error::BalancerCrashed);
                     },
 
                     //From IPC Listener
                     HandlerCommand::EstablishingConnection =>
                         try!(self.sender.balancer_sender.send(&HandlerToBalancer::ConnectionEstablished), Error::BalancerCrashed),
-                    HandlerCommand::AcceptConnection(server_type,server_id,connection_id,address,balancer_connection_id) =>
-                        do_sender_transaction![self.sender.accept_connection(server_type,server_id,connection_id,address,balancer_connection_id)],
+                    HandlerCommand::AcceptConnection(server_type,server_id,connection_id,address,balancer_connection_id) => {
+                        if server_type==ServerType::Storage {
+                            self.server_ids.insert(balancer_connection_id, server_id);
+                        }
+
+                        do_sender_transaction![self.sender.accept_connection(server_type,server_id,connection_id,address,balancer_connection_id)];
+                    },
                     HandlerCommand::ConnectionAccepted(server_type,connection_id,set_connection_id) =>
                         do_sender_transaction![self.sender.connection_accepted(server_type,connection_id,set_connection_id)],
                     HandlerCommand::Connected(server_type,connection_id) =>
                         do_sender_transaction![self.sender.connected(server_type,connection_id)],
-                    HandlerCommand::EachSecond =>
-                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::StillAlive), Error::BalancerCrashed),
+                    #[cfg(feature="farewell")]
+                    HandlerCommand::Goodbye(server_type,connection_id) =>
+                        self.goodbye_received(server_type,connection_id)?,
+                    #[cfg(feature="farewell")]
+                    HandlerCommand::GoodbyeAccepted(server_type,connection_id) =>
+                        self.farewell_accepted(server_type,connection_id)?,
+
+                    //From Timer
+                    HandlerCommand::Timer(handle, event) =>
+                        self.handle_timer_event(handle, event)?,
 
                     //From automat
+                    HandlerCommand::StateTimedOut(state) => {
+                        match state {
+                            State::Working(WorkingState::MapSaving) => self.saving=None,//Манифест снимка не записывается
+                            State::Working(WorkingState::MapGeneration) => self.discard_generated_map(),
+                            _ => self.map=None,//Карта не загружена или уже закрывалась
+                        }
+
+                        self.complete_stage()?;
+
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::StateTimeout(state.to_string())), Error::BalancerCrashed);
+                    },
+                    HandlerCommand::MapGenerationAborted =>
+                        self.discard_generated_map(),
+                    HandlerCommand::MapGenerationFailed => {
+                        warn!("Map generation has failed");
+                        self.discard_generated_map();
+                        self.complete_stage()?;
+                    },
+                    HandlerCommand::StateChanged(state, queue_depth) => {
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::StateReport(state.to_string(), queue_depth as u32)), Error::BalancerCrashed);
+                        self.update_game_loop(&state)?;
+                    },
+                    HandlerCommand::InvalidTransition(state, event) =>
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::InvalidTransition(state.to_string(), event.to_string())), Error::BalancerCrashed),
                     HandlerCommand::Familiarize(familiarity_lists) =>
-                        do_sender_transaction!(self.sender.familiarize(familiarity_lists)),
-                    HandlerCommand::FamiliarityFinished =>
-                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::FamiliarityFinished), Error::BalancerCrashed),
+                        self.push_control(ControlTask::Familiarize(familiarity_lists))?,
+                    HandlerCommand::FamiliarityFinished => {
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::FamiliarityFinished), Error::BalancerCrashed);
+                        self.resume_recovered_stage()?;
+                    },
+                    HandlerCommand::HotJoin(familiarity_lists) =>
+                        self.push_control(ControlTask::Familiarize(familiarity_lists))?,//В списках только сервера, подключившиеся после знакомства
+                    #[cfg(feature="hot_join")]
+                    HandlerCommand::HotJoinFinished =>
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::HotJoinFinished), Error::BalancerCrashed),
+                    #[cfg(not(feature="hot_join"))]
+                    HandlerCommand::HotJoinFinished =>
+                        debug!("Hot join finished"),
+                    HandlerCommand::SayGoodbye =>
+                        self.push_control(ControlTask::SayGoodbye)?,
 
                     HandlerCommand::SenderCommand(sender_command) =>
                         self.handle_sender_command(sender_command)?,
 
-                    HandlerCommand::GenerateMap(map_name) => {
-                        use common_sender::StorageTrait;
-                        let message=::common_messages::HandlerToStorage::CreateResource(0, vec![1,2,3]);
-                        do_sender_transaction!( self.sender.storages.send(ConnectionID::new(0,1),0,&message) );
-                        let message=::common_messages::HandlerToStorage::CreateResource(0, vec![1;1000]);
-                        do_sender_transaction!( self.sender.storages.send(ConnectionID::new(0,1),0,&message) );
-                        //self.resource_heap.create_map(map_name)?;
-                        do_automat_transaction![self.automat.process_signal(AutomatSignal::ThreadIsReady(ThreadSource::Handler))];
+                    HandlerCommand::GenerateMap(map_name, phase) =>
+                        self.generate_map(map_name, phase)?,
+                    HandlerCommand::MapGenerated => {
+                        self.complete_stage()?;
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapGenerated), Error::BalancerCrashed);
                     },
-                    HandlerCommand::MapGenerated =>
-                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapGenerated), Error::BalancerCrashed),
-                    HandlerCommand::CloseMap => {
-                        //self.resource_heap.close_map()?;
-                        do_automat_transaction![self.automat.process_signal(AutomatSignal::ThreadIsReady(ThreadSource::Handler))];
+                    HandlerCommand::LoadMap(map_name) =>
+                        self.load_map(map_name)?,
+                    HandlerCommand::BuildMap(phase) =>
+                        self.build_map(phase)?,
+                    HandlerCommand::MapLoaded => {
+                        self.complete_stage()?;
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapLoaded), Error::BalancerCrashed);
+                    },
+                    HandlerCommand::MapLoadingFailed => {
+                        self.map=None;
+                        self.complete_stage()?;
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapLoadingFailed), Error::BalancerCrashed);
+                    },
+                    HandlerCommand::ResourceNotFound(resource_id) =>
+                        self.resource_not_found(resource_id)?,
+                    HandlerCommand::ResourceSaved(resource_id) =>
+                        self.resource_saved(resource_id)?,
+                    HandlerCommand::SaveMap(map_name, phase) =>
+                        self.save_map(map_name, phase)?,
+                    HandlerCommand::SnapshotMap(map_name, phase) =>
+                        self.save_map_to_storage(map_name, phase)?,
+                    HandlerCommand::MapSaved => {
+                        self.complete_stage()?;
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapSaved), Error::BalancerCrashed);
+                    },
+                    HandlerCommand::MapSavingFailed => {
+                        self.saving=None;
+                        self.complete_stage()?;
+                        try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapSavingFailed), Error::BalancerCrashed);
+                    },
+                    HandlerCommand::CloseMap(phase) => {
+                        self.complete_stage()?;//Прерванная генерация больше не понадобится
+
+                        match self.map.take() {
+                            Some(map) => Self::save_manifest(&map),
+                            None => {},
+                        }
+
+                        do_automat_transaction![self.automat.process_signal(AutomatSignal::ThreadIsReady(ThreadSource::Handler, phase))];
                     },
                     HandlerCommand::MapClosed =>
                         try!(self.sender.balancer_sender.send(&HandlerToBalancer::MapClosed), Error::BalancerCrashed),
 
                     _ => panic!("Unexpected type of HandlerCommand")
                 }
+
+                //commands may push tasks too
+                if wait_tasks && inline_tasks {
+                    wait_tasks=!do_tasks_queue_transaction!(self.tasks_queue.is_task());
+                }
+            }
+
+            //process task, TasksQueue picks it from the lanes by priority
+            if inline_tasks {
+                match do_tasks_queue_transaction!(self.tasks_queue.pop(0)) {
+                    Some(entry) => self.execute_task(entry)?,
+                    None => {}
+                }
+            }
+        }
+    }
+
+    fn handle_timer_event(&mut self, handle:TimerHandle, event:TimerEvent) -> Result<(),Error> {
+        match event {
+            TimerEvent::EachSecond => {
+                self.push_control(ControlTask::Heartbeat)?;
+                self.game_loop.report();
+            },
+            TimerEvent::StateTimeout =>
+                do_automat_transaction![self.automat.process_signal(AutomatSignal::StateTimeout(handle))],
+            TimerEvent::Tick if self.game_loop.is_woken_by(handle) =>
+                self.run_ticks(),
+            TimerEvent::Tick => {},//Цикл уже остановлен
+        }
+
+        ok!()
+    }
+
+    ///Игровой цикл работает только в состоянии Playing
+    fn update_game_loop(&mut self, state:&State) -> Result<(),Error> {
+        match *state {
+            State::Working(WorkingState::Playing) => {
+                if !self.game_loop.is_running() {
+                    let handle=do_timer_transaction!(self.timer.schedule_every(self.game_loop.step(), TimerEvent::Tick));
+                    self.game_loop.start(handle);
+                }
+            },
+            _ => {
+                match self.game_loop.stop() {
+                    Some(handle) => do_timer_transaction!(self.timer.cancel(handle)),
+                    None => {},
+                }
+            }
+        }
+
+        ok!()
+    }
+
+    ///Выполняет накопившиеся тики
+    fn run_ticks(&mut self) {
+        let ticks=self.game_loop.due_ticks(Instant::now());
+
+        for _ in 0..ticks {
+            let started=Instant::now();
+            self.tick();
+            self.game_loop.record_tick(started.elapsed());
+        }
+    }
+
+    ///Шаг симуляции мира
+    fn tick(&mut self) {
+        //TODO игровая логика
+    }
+
+    ///Генерирует карту графом задач, когда граф выполнится, Автомат получит ThreadIsReady, а если он провалится - MapGenerationFailed
+    fn generate_map(&mut self, map_name:String, phase:Phase) -> Result<(),Error> {
+        let recovered_nodes=self.begin_stage(MapStage::Generate(map_name.clone()))?;
+
+        let storage=match Self::choose_storage(&self.storage_servers, &map_name) {
+            Some(storage) => storage,
+            None => {
+                warn!("Can not generate map \"{}\": no Storage with known ServerID is connected", map_name);
+                do_automat_transaction![self.automat.process_signal(AutomatSignal::MapGenerationFailed(phase))];
+
+                return ok!();
+            }
+        };
+
+        //Генерация, прерванная падением, продолжается с уже созданными ресурсами. Если манифеста нет,
+        //значит генерация была прервана и отброшена, тогда она начинается заново
+        let (map, recovered_nodes)=match recovered_nodes.map(|nodes| (Map::read(map_name.clone()), nodes)) {
+            Some((Ok(map), nodes)) => (Ok(map), Some(nodes)),
+            Some((Err(_), nodes)) => {
+                for node in nodes {
+                    do_tasks_queue_transaction!(self.tasks_queue.journal_complete(Some(node.id)));
+                }
+
+                self.discard_recovered_tasks()?;
+
+                (Map::create(map_name.clone(), storage), None)
+            },
+            None => (Map::create(map_name.clone(), storage), None),
+        };
+
+        let map=match map {
+            Ok(map) => map,
+            Err(error) => {
+                warn!("Can not generate map: {}", error);
+                do_automat_transaction![self.automat.process_signal(AutomatSignal::MapGenerationFailed(phase))];
+
+                return ok!();
+            }
+        };
+
+        self.map=Some(map);
+
+        //Шаги, не успевшие до таймаута стадии, завершаются с TaskError::Expired, и граф проваливается
+        let deadline=self.map_generation_timeout.map(|timeout| Instant::now() + timeout);
+        let map_token=do_tasks_queue_transaction!(self.tasks_queue.map_token());
+        let step=|step| TaskEntry::with_deadline(Task::GenerateMap(map_name.clone(), step), map_token.clone(), deadline);
+
+        let mut graph=match recovered_nodes {
+            Some(nodes) => {
+                info!("Map \"{}\" generation is resumed with {} steps left", map_name, nodes.len());
+                self.replay_recovered_tasks()?;
+
+                TaskGraph::recover(nodes, &map_token, deadline)
+            },
+            None => {
+                let mut graph=TaskGraph::new();
+                let terrain=graph.add(step(MapGenerationStep::Terrain), &[]);
+                let regions=graph.add(step(MapGenerationStep::Regions), &[terrain]);
+                let objects=graph.add(step(MapGenerationStep::Objects), &[regions]);
+                graph.add(step(MapGenerationStep::Persistence), &[objects]);
+
+                graph
+            },
+        };
+
+        graph.journal_in(self.stage);
+        graph.on_complete(AutomatSignal::ThreadIsReady(ThreadSource::Handler, phase));
+        graph.on_failure(AutomatSignal::MapGenerationFailed(phase));
+
+        match do_tasks_queue_transaction!(self.tasks_queue.push_graph(graph)) {
+            Some(signal) => do_automat_transaction![self.automat.process_signal(signal)],
+            None => {},
+        }
+
+        ok!()
+    }
+
+    ///Начинает стадию карты и записывает её в журнал. Если это стадия, прерванная падением, она продолжается
+    ///под прежним номером, тогда возвращаются невыполненные задачи её графа
+    fn begin_stage(&mut self, stage:MapStage) -> Result<Option<Vec<RecoveredNode>>,Error> {
+        self.complete_stage()?;
+
+        match self.recovered_stage.take() {
+            Some(recovered_stage) => {
+                if recovered_stage.stage==stage {
+                    info!("Map stage {:?} is resumed from journal", stage);
+                    self.stage=Some(recovered_stage.id);
+
+                    return ok!(Some(recovered_stage.graph_nodes));
+                }
+
+                let waits_for_map=match (&recovered_stage.stage, &stage) {
+                    (&MapStage::Save(ref map_name, _), &MapStage::Load(ref loading_map_name)) => map_name==loading_map_name,
+                    _ => false,
+                };
+
+                if waits_for_map {
+                    //Восстановленное сохранение продолжится, когда его карта будет загружена
+                    self.recovered_stage=Some(recovered_stage);
+                }else{
+                    warn!("Map stage {:?} from journal is superseded by {:?}", recovered_stage.stage, stage);
+                    do_tasks_queue_transaction!(self.tasks_queue.journal_complete(Some(recovered_stage.id)));
+                    self.discard_recovered_tasks()?;
+                }
+            },
+            None => {},
+        }
+
+        self.stage=do_tasks_queue_transaction!(self.tasks_queue.journal_stage(&stage));
+
+        ok!(None)
+    }
+
+    ///Стадия карты завершена, успешно или нет, после перезапуска её повторять не нужно.
+    ///Прерванная генерация завершается только следующей стадией, закрытием карты или выключением без перезапуска
+    fn complete_stage(&mut self) -> Result<(),Error> {
+        let stage=self.stage.take();
+        do_tasks_queue_transaction!(self.tasks_queue.journal_complete(stage));
+
+        ok!()
+    }
+
+    ///После знакомства Handler отправляет Автомату команды стадии, прерванной падением,
+    ///сохранение карты повторяется после её загрузки
+    fn resume_recovered_stage(&mut self) -> Result<(),Error> {
+        let commands=match self.recovered_stage {
+            Some(ref recovered_stage) => {
+                match recovered_stage.stage {
+                    MapStage::Generate(ref map_name) =>
+                        vec![AutomatCommand::GenerateMap(map_name.clone())],
+                    MapStage::Load(ref map_name) =>
+                        vec![AutomatCommand::LoadMap(map_name.clone())],
+                    MapStage::Save(ref map_name, ref snapshot_name) =>
+                        vec![AutomatCommand::LoadMap(map_name.clone()), AutomatCommand::SaveMap(snapshot_name.clone())],
+                }
+            },
+            None => return ok!(),
+        };
+
+        info!("Resuming {} map commands from journal", commands.len());
+
+        for command in commands {
+            do_automat_transaction![self.automat.send_command(command)];
+        }
+
+        ok!()
+    }
+
+    ///Возвращает в очередь ответы Storage из журнала, когда восстановленная стадия снова создала карту
+    fn replay_recovered_tasks(&mut self) -> Result<(),Error> {
+        let recovered_tasks=std::mem::replace(&mut self.recovered_tasks, Vec::new());
+
+        if recovered_tasks.len() > 0 {
+            info!("Replaying {} tasks from journal", recovered_tasks.len());
+        }
+
+        for (journal_id, task) in recovered_tasks {
+            do_tasks_queue_transaction!(self.tasks_queue.replay(journal_id, task));
+        }
+
+        ok!()
+    }
+
+    ///Ответы Storage из журнала относятся к карте, которую уже не восстановить
+    fn discard_recovered_tasks(&mut self) -> Result<(),Error> {
+        let recovered_tasks=std::mem::replace(&mut self.recovered_tasks, Vec::new());
+
+        for (journal_id, _) in recovered_tasks {
+            do_tasks_queue_transaction!(self.tasks_queue.journal_complete(Some(journal_id)));
+        }
+
+        ok!()
+    }
+
+    ///Отправляет Goodbye всем серверам, когда все подтвердят его, Автомат получит FarewellFinished
+    fn say_goodbye(&mut self) -> Result<(),Error> {
+        self.farewell.clear();
+        self.send_goodbye()?;
+
+        debug!("Saying goodbye to {} servers", self.farewell.len());
+
+        if self.farewell.is_empty() {
+            do_automat_transaction![self.automat.process_signal(AutomatSignal::FarewellFinished)];
+        }
+
+        ok!()
+    }
+
+    ///Отправляет Goodbye всем подключённым серверам и запоминает, от кого ждать подтверждения
+    #[cfg(feature="farewell")]
+    fn send_goodbye(&mut self) -> Result<(),Error> {
+        use common_sender::StorageTrait;
+
+        for &connection_id in self.storages.iter() {
+            do_sender_transaction!( self.sender.storages.send(connection_id,0,&HandlerToStorage::Goodbye), self.farewell.push((ServerType::Storage,connection_id)) );
+        }
+
+        for &connection_id in self.handlers.iter() {
+            do_sender_transaction!( self.sender.handlers.send(connection_id,0,&HandlerToHandler::Goodbye), self.farewell.push((ServerType::Handler,connection_id)) );
+        }
+
+        ok!()
+    }
+
+    ///Без протокола прощания подтверждений ждать не от кого
+    #[cfg(not(feature="farewell"))]
+    fn send_goodbye(&mut self) -> Result<(),Error> {
+        warn!("Handler is built without farewell, {} servers are not told about shutdown", self.storages.len() + self.handlers.len());
+
+        ok!()
+    }
+
+    ///Сервер выключается: подтверждаем его Goodbye и забываем о нём, ресурсы карты больше не распределяются на него.
+    ///Если мы сами с ним прощаемся, то его Goodbye заменяет подтверждение
+    #[cfg(feature="farewell")]
+    fn goodbye_received(&mut self, server_type:ServerType, connection_id:ConnectionID) -> Result<(),Error> {
+        use common_sender::StorageTrait;
+
+        info!("{} ({}) says goodbye", server_type, connection_id);
+
+        match server_type {
+            ServerType::Storage => {
+                do_sender_transaction!( self.sender.storages.send(connection_id,0,&HandlerToStorage::GoodbyeAccepted) );
+                self.storages.retain(|&storage| storage!=connection_id);
+                self.storage_servers.retain(|&(_,storage)| storage!=connection_id);
+
+                let saving_phase=match self.saving {
+                    Some(ref saving) if saving.connection_id==connection_id => Some(saving.phase),
+                    _ => None,
+                };
+
+                match saving_phase {
+                    Some(phase) => {
+                        warn!("Storage ({}) has left before it saved the map", connection_id);
+                        self.map_saving_failed(phase)?;
+                    },
+                    None => {},
+                }
+            },
+            ServerType::Handler => {
+                do_sender_transaction!( self.sender.handlers.send(connection_id,0,&HandlerToHandler::GoodbyeAccepted) );
+                self.handlers.retain(|&handler| handler!=connection_id);
+            },
+            _ => warn!("Unexpected goodbye from {}", server_type),
+        }
+
+        self.farewell_accepted(server_type, connection_id)
+    }
+
+    ///Сервер подтвердил наше Goodbye
+    #[cfg(feature="farewell")]
+    fn farewell_accepted(&mut self, server_type:ServerType, connection_id:ConnectionID) -> Result<(),Error> {
+        let index=match self.farewell.iter().position(|&(farewell_type,farewell_id)| farewell_type==server_type && farewell_id==connection_id) {
+            Some(index) => index,
+            None => return ok!(),
+        };
+
+        self.farewell.remove(index);
+
+        if self.farewell.is_empty() {
+            debug!("All servers have accepted goodbye");
+            do_automat_transaction![self.automat.process_signal(AutomatSignal::FarewellFinished)];
+        }
+
+        ok!()
+    }
+
+    ///Читает манифест карты, просит Storage-сервера загрузить карту и запрашивает её ресурсы,
+    ///когда все ресурсы будут получены, Автомат получит MapLoadedFromStorage
+    fn load_map(&mut self, map_name:String) -> Result<(),Error> {
+        use common_sender::StorageTrait;
+
+        let is_recovered=self.begin_stage(MapStage::Load(map_name.clone()))?.is_some();
+
+        let map=match Map::read(map_name.clone()) {
+            Ok(map) => map,
+            Err(error) => {
+                warn!("Can not load map: {}", error);
+                return self.map_loading_failed();
+            }
+        };
+
+        let resource_ids=map.resource_ids();
+        let storage=Self::storage_of(&self.storage_servers, &map);
+        let storage_id=map.storage;
+        self.map=Some(map);
+
+        if is_recovered {
+            self.replay_recovered_tasks()?;
+        }
+
+        if resource_ids.len()==0 {
+            do_automat_transaction![self.automat.process_signal(AutomatSignal::MapLoadedFromStorage)];
+
+            return ok!();
+        }
+
+        let connection_id=match storage {
+            Some(connection_id) => connection_id,
+            None => {
+                warn!("Can not load map \"{}\": Storage {} of map is not connected", map_name, storage_id);
+                return self.map_loading_failed();
+            }
+        };
+
+        //Сообщения одного соединения обрабатываются по порядку, поэтому GetResource придут к Storage после LoadMap
+        do_sender_transaction!( self.sender.storages.send(connection_id,0,&HandlerToStorage::LoadMap(map_name.clone())) );
+
+        for resource_id in resource_ids.iter() {
+            do_sender_transaction!( self.sender.storages.send(connection_id,0,&HandlerToStorage::GetResource(resource_id.code())) );
+        }
+
+        ok!()
+    }
+
+    ///Соединение с Storage, ServerID которого записан в манифесте карты
+    fn storage_of(storage_servers:&[(ServerID,ConnectionID)], map:&Map) -> Option<ConnectionID> {
+        storage_servers.iter().find(|&&(server_id,_)| server_id==map.storage).map(|&(_,connection_id)| connection_id)
+    }
+
+    ///Storage для новой карты выбирается по хешу её имени среди Storage-серверов, упорядоченных по ServerID,
+    ///дальше карта остаётся за ним независимо от состава и порядка подключений
+    fn choose_storage(storage_servers:&[(ServerID,ConnectionID)], map_name:&String) -> Option<ServerID> {
+        use std::hash::{Hash,Hasher};
+        use std::collections::hash_map::DefaultHasher;
+
+        if storage_servers.is_empty() {
+            return None;
+        }
+
+        let mut server_ids:Vec<ServerID>=storage_servers.iter().map(|&(server_id,_)| server_id).collect();
+        server_ids.sort();
+
+        let mut hasher=DefaultHasher::new();
+        map_name.hash(&mut hasher);
+
+        Some(server_ids[(hasher.finish() % server_ids.len() as u64) as usize])
+    }
+
+    ///Storage не нашёл ресурс загружаемой карты, загрузка проваливается
+    fn resource_not_found(&mut self, resource_id:ResourceID) -> Result<(),Error> {
+        let is_map_resource=match self.map {
+            Some(ref map) => map.has_resource(&resource_id) && !map.is_loaded(),
+            None => false,
+        };
+
+        if !is_map_resource {
+            return ok!();
+        }
+
+        warn!("Storage has not found resource {} of map", resource_id);
+        self.map_loading_failed()
+    }
+
+    ///Карта сбрасывается сразу, чтобы ответы Storage, пришедшие до отката Автомата, были проигнорированы
+    fn map_loading_failed(&mut self) -> Result<(),Error> {
+        self.map=None;
+        do_automat_transaction![self.automat.process_signal(AutomatSignal::MapLoadingFailed)];
+
+        ok!()
+    }
+
+    ///Сбрасывает карту, генерация которой не завершилась, вместе с её манифестом, чтобы имя можно было использовать снова
+    fn discard_generated_map(&mut self) {
+        match self.map.take() {
+            Some(map) => {
+                let map_name=map.name.clone();
+
+                match map.remove() {
+                    Ok(_) => {},
+                    Err(error) => warn!("Can not remove manifest of map \"{}\": {}", map_name, error),
+                }
+            },
+            None => {},
+        }
+    }
+
+    ///Строит карту по полученным от Storage ресурсам
+    fn build_map(&mut self, phase:Phase) -> Result<(),Error> {
+        match self.map {
+            Some(ref map) => debug!("Building map \"{}\"", map.name),
+            None => {},
+        }
+
+        do_automat_transaction![self.automat.process_signal(AutomatSignal::ThreadIsReady(ThreadSource::Handler, phase))];
+
+        ok!()
+    }
+
+    ///Останавливает выполнение задач и дожидается уже выполняющихся. Их результаты уже в канале Handler-а,
+    ///поэтому снимок карты делается по SnapshotMap, который придёт после них
+    fn save_map(&mut self, map_name:String, phase:Phase) -> Result<(),Error> {
+        let current_map_name=self.map.as_ref().map(|map| map.name.clone());
+
+        match current_map_name {
+            Some(current_map_name) => {self.begin_stage(MapStage::Save(current_map_name, map_name.clone()))?;},
+            None => {},
+        }
+
+        do_tasks_queue_transaction!(self.tasks_queue.quiesce());
+
+        channel_send!(self.handler_sender, HandlerCommand::SnapshotMap(map_name, phase));
+
+        ok!()
+    }
+
+    ///Сбрасывает снимок карты в её Storage, сама карта продолжает работать под прежним именем.
+    ///Манифест снимка под новым именем записывается, когда Storage подтвердит все ресурсы
+    fn save_map_to_storage(&mut self, map_name:String, phase:Phase) -> Result<(),Error> {
+        use common_sender::StorageTrait;
+
+        do_tasks_queue_transaction!(self.tasks_queue.release());//Снимок уже не зависит от задач
+
+        let snapshot=match (self.map.as_ref(), Map::check_name(&map_name)) {
+            (Some(map), Ok(_)) => map.snapshot(map_name),
+            (None, _) => {
+                warn!("Handler has no map to save");
+                return self.map_saving_failed(phase);
+            },
+            (_, Err(error)) => {
+                warn!("Can not save map: {}", error);
+                return self.map_saving_failed(phase);
+            },
+        };
+
+        let connection_id=match Self::storage_of(&self.storage_servers, &snapshot) {
+            Some(connection_id) => connection_id,
+            None => {
+                warn!("Can not save map \"{}\": Storage {} is not connected", snapshot.name, snapshot.storage);
+                return self.map_saving_failed(phase);
             }
+        };
 
-            //process task
+        let mut unsaved=Vec::new();
 
+        for (resource_id, data) in snapshot.loaded_resources() {
+            do_sender_transaction!( self.sender.storages.send(connection_id,0,&HandlerToStorage::SaveResource(resource_id.code(), data.to_vec())) );
+            unsaved.push(resource_id);
         }
+
+        self.saving=Some(SavingSnapshot{
+            snapshot,
+            phase,
+            connection_id,
+            unsaved,
+        });
+
+        self.snapshot_saved()
+    }
+
+    ///Storage подтвердил сохранение ресурса снимка
+    fn resource_saved(&mut self, resource_id:ResourceID) -> Result<(),Error> {
+        match self.saving {
+            Some(ref mut saving) => saving.unsaved.retain(|unsaved| unsaved.code()!=resource_id.code()),
+            None => {
+                warn!("Storage has saved resource {}, but no map is being saved", resource_id);
+                return ok!();
+            },
+        }
+
+        self.snapshot_saved()
+    }
+
+    ///Когда все ресурсы снимка сохранены, записывает его манифест, и Handler готов
+    fn snapshot_saved(&mut self) -> Result<(),Error> {
+        let is_saved=match self.saving {
+            Some(ref saving) => saving.unsaved.is_empty(),
+            None => false,
+        };
+
+        if !is_saved {
+            return ok!();
+        }
+
+        let saving=self.saving.take().unwrap();
+
+        match saving.snapshot.save() {
+            Ok(_) => {},
+            Err(error) => {
+                warn!("Can not save manifest of map \"{}\": {}", saving.snapshot.name, error);
+                return self.map_saving_failed(saving.phase);
+            }
+        }
+
+        do_automat_transaction![self.automat.process_signal(AutomatSignal::ThreadIsReady(ThreadSource::Handler, saving.phase))];
+
+        ok!()
+    }
+
+    fn map_saving_failed(&mut self, phase:Phase) -> Result<(),Error> {
+        self.saving=None;
+        do_automat_transaction![self.automat.process_signal(AutomatSignal::MapSavingFailed(phase))];
+
+        ok!()
+    }
+
+    ///Записывает манифест карты, ошибка записи не фатальна
+    fn save_manifest(map:&Map) {
+        match map.save() {
+            Ok(_) => {},
+            Err(error) => warn!("Can not save manifest of map \"{}\": {}", map.name, error),
+        }
+    }
+
+    ///Выполняет задачу в потоке Handler, если рабочих потоков нет
+    fn execute_task(&mut self, entry:TaskEntry) -> Result<(),Error> {
+        let receipt=entry.receipt();
+        let result=entry.execute();
+        let succeeded=result.is_ok();
+
+        self.handle_task_result(result)?;
+
+        match do_tasks_queue_transaction!(self.tasks_queue.finish(receipt, succeeded)) {
+            Some(signal) => do_automat_transaction![self.automat.process_signal(signal)],
+            None => {},
+        }
+
+        ok!()
+    }
+
+    ///Применяет результат задачи, выполненной рабочим потоком или самим Handler
+    fn handle_task_result(&mut self, result:Result<TaskOutput,TaskError>) -> Result<(),Error> {
+        use common_sender::StorageTrait;
+
+        match result {
+            Ok(TaskOutput::Done) => {},
+            Ok(TaskOutput::ToStorage(messages)) => {
+                let connection_id=match self.map {
+                    Some(ref mut map) => {
+                        for message in messages.iter() {
+                            match *message {
+                                HandlerToStorage::CreateResource(_, ref data) => map.expect_resource(data.clone()),
+                                _ => {},
+                            }
+                        }
+
+                        Self::storage_of(&self.storage_servers, map)
+                    },
+                    None => None,
+                };
+
+                match connection_id {
+                    Some(connection_id) => {
+                        for message in messages.iter() {
+                            do_sender_transaction!( self.sender.storages.send(connection_id,0,message) );
+                        }
+                    },
+                    None => warn!("{} messages to Storage are dropped, there is no map or no Storage is connected", messages.len()),
+                }
+            },
+            Ok(TaskOutput::ResourceCreated(resource_id)) => {
+                match self.map {
+                    Some(ref mut map) => {
+                        map.add_resource(resource_id);
+                        Self::save_manifest(map);
+                    },
+                    None => {},
+                }
+            },
+            Ok(TaskOutput::ResourceLoaded(resource_id, data)) => {
+                let loaded=match self.map {
+                    Some(ref mut map) => {
+                        let was_loaded=map.is_loaded();
+
+                        map.set_resource_data(&resource_id, data) && !was_loaded && map.is_loaded()
+                    },
+                    None => false,
+                };
+
+                if loaded {
+                    do_automat_transaction![self.automat.process_signal(AutomatSignal::MapLoadedFromStorage)];
+                }
+            },
+            Ok(TaskOutput::Control(control_task)) =>
+                self.apply_control(control_task)?,
+            Err(error) => warn!("{}", error),
+        }
+
+        ok!()
+    }
+
+    ///Служебная работа проходит через TasksQueue с приоритетом Control, поэтому она не ждёт очереди игровых задач
+    ///и выполняется даже во время паузы
+    fn push_control(&mut self, control_task:ControlTask) -> Result<(),Error> {
+        do_tasks_queue_transaction!(self.tasks_queue.push(Task::Control(control_task)));
+
+        ok!()
+    }
+
+    fn apply_control(&mut self, control_task:ControlTask) -> Result<(),Error> {
+        match control_task {
+            ControlTask::Heartbeat =>
+                try!(self.sender.balancer_sender.send(&HandlerToBalancer::StillAlive), Error::BalancerCrashed),
+            ControlTask::Familiarize(familiarity_lists) =>
+                do_sender_transaction!(self.sender.familiarize(familiarity_lists)),
+            ControlTask::SayGoodbye =>
+                self.say_goodbye()?,
+        }
+
+        ok!()
     }
 
     fn lifecycle_shutdown(&mut self) -> Result<(),Error> {
@@ -282,7 +1163,7 @@ impl Handler {
         try_send![self.ipc_listener_sender, IpcListenerCommand::HandlerFinished];
     }
 
-    fn handle_sender_command(&self, sender_command:SenderCommand) -> Result<(),Error> {
+    fn handle_sender_command(&mut self, sender_command:SenderCommand) -> Result<(),Error> {
         use common_messages::ToBalancerMessage;
 
         match sender_command {
@@ -292,8 +1173,23 @@ impl Handler {
                 try!(self.sender.balancer_sender.send(&HandlerToBalancer::connection_failed(server_type,connection_id)), Error::BalancerCrashed),
             SenderCommand::TransactionFailed(server_type, connection_id, error, basic_state) =>
                 warn!("Sender transaction failed {} {} {}", server_type, connection_id, error),
-            SenderCommand::Connected(server_type, connection_id, balancer_connection_id, via_connection_id) =>
-                info!("Connected to {} ({}) {} via {}",server_type, connection_id, balancer_connection_id, via_connection_id), //TODO пока ничего не делаем
+            SenderCommand::Connected(server_type, connection_id, balancer_connection_id, via_connection_id) => {
+                info!("Connected to {} ({}) {} via {}",server_type, connection_id, balancer_connection_id, via_connection_id);
+
+                match server_type {
+                    ServerType::Storage if !self.storages.contains(&connection_id) => {
+                        self.storages.push(connection_id);
+
+                        //ServerID известен только для серверов, подключившихся к нам через AcceptConnection
+                        match self.server_ids.remove(&balancer_connection_id) {
+                            Some(server_id) => self.storage_servers.push((server_id, connection_id)),
+                            None => warn!("ServerID of Storage ({}) is unknown, it will not store maps", connection_id),
+                        }
+                    },
+                    ServerType::Handler if !self.handlers.contains(&connection_id) => self.handlers.push(connection_id),
+                    _ => {},
+                }
+            },
             SenderCommand::ConnectedToServers(server_type) =>
                 do_automat_transaction![self.automat.process_signal(AutomatSignal::ConnectedToServers(server_type))],
         }
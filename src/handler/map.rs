@@ -0,0 +1,221 @@
+//!Карта, с которой работает Handler. Список ресурсов карты(манифест) хранится на диске,
+//!поэтому однажды сгенерированную карту можно загрузить из Storage и после перезапуска.
+//!Все ресурсы карты хранятся в одном Storage, его ServerID записывается в манифест, так что карта находит свой Storage
+//!независимо от того, какие ещё Storage-сервера подключены и в каком порядке.
+
+use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+
+use std::io::{Read,Write};
+use std::fs::{File,OpenOptions};
+use std::path::PathBuf;
+use std::collections::VecDeque;
+
+use ::ResourceID;
+use ::ServerID;
+
+const MAPS_DIRECTORY:&'static str = "maps";
+const STORAGE_PREFIX:&'static str = "storage ";
+
+///Карта
+#[derive(Clone)]
+pub struct Map {
+    pub name:String,
+    ///Storage, хранящий ресурсы карты, задаётся при генерации и не меняется при сохранении карты под другим именем
+    pub storage:ServerID,
+    ///Ресурсы карты и их данные, если они уже получены от Storage
+    resources:Vec<(ResourceID,Option<Vec<u8>>)>,
+    ///Данные ресурсов, отправленных в Storage на создание, Storage отвечает ResourceCreated в том же порядке
+    creating:VecDeque<Vec<u8>>,
+}
+
+define_error!( Error,
+    IOError(io_error:Box<std::io::Error>) =>
+        "IO Error: {1}",
+    ManifestError(map_name:String, message:String) =>
+        "Manifest of map \"{1}\" is broken: {2}",
+    BadMapName(map_name:String) =>
+        "Map name \"{1}\" is not allowed, it may contain only latin letters, digits, '_' and '-'",
+    MapExists(map_name:String) =>
+        "Map \"{1}\" already exists"
+);
+
+impl_from_error!(std::io::Error => Error::IOError);
+
+impl Map {
+    ///Новая карта без ресурсов, её пустой манифест сразу записывается на диск.
+    ///Существующая карта с тем же именем не перезаписывается
+    pub fn create(name:String, storage:ServerID) -> Result<Self,Error> {
+        Self::check_name(&name)?;
+        std::fs::create_dir_all(MAPS_DIRECTORY)?;
+
+        match OpenOptions::new().write(true).create_new(true).open(Self::manifest_path(&name)) {
+            Ok(_) => {},
+            Err(ref error) if error.kind()==std::io::ErrorKind::AlreadyExists => return err!(Error::MapExists, name),
+            Err(error) => return err!(Error::IOError, Box::new(error)),
+        }
+
+        let map=Map {
+            name,
+            storage,
+            resources:Vec::new(),
+            creating:VecDeque::new(),
+        };
+
+        ok!(map)
+    }
+
+    ///Читает манифест карты, данные ресурсов ещё не получены
+    pub fn read(name:String) -> Result<Self,Error> {
+        Self::check_name(&name)?;
+
+        let mut content=String::new();
+        File::open(Self::manifest_path(&name))?.read_to_string(&mut content)?;
+
+        let mut storage=None;
+        let mut resources=Vec::new();
+
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            if line.starts_with(STORAGE_PREFIX) {
+                match line[STORAGE_PREFIX.len()..].parse::<ServerID>() {
+                    Ok(server_id) => storage=Some(server_id),
+                    Err(_) => return err!(Error::ManifestError, name, format!("bad storage \"{}\"", line)),
+                }
+
+                continue;
+            }
+
+            match line.parse::<u64>() {
+                Ok(code) => resources.push((ResourceID::from(code), None)),
+                Err(_) => return err!(Error::ManifestError, name, format!("bad resource id \"{}\"", line)),
+            }
+        }
+
+        let storage=match storage {
+            Some(storage) => storage,
+            None => return err!(Error::ManifestError, name, "storage is not specified".to_string()),
+        };
+
+        let map=Map {
+            name,
+            storage,
+            resources,
+            creating:VecDeque::new(),
+        };
+
+        ok!(map)
+    }
+
+    ///Записывает манифест карты
+    pub fn save(&self) -> Result<(),Error> {
+        std::fs::create_dir_all(MAPS_DIRECTORY)?;
+
+        let mut content=String::with_capacity(self.resources.len()*16 + 32);
+        content.push_str(&format!("{}{}\n", STORAGE_PREFIX, self.storage));
+
+        for &(ref resource_id,_) in self.resources.iter() {
+            content.push_str(&format!("{}\n", resource_id.code() as u64));
+        }
+
+        File::create(Self::manifest_path(&self.name))?.write_all(content.as_bytes())?;
+
+        ok!()
+    }
+
+    ///Удаляет манифест карты, генерация которой не завершилась
+    pub fn remove(self) -> Result<(),Error> {
+        std::fs::remove_file(Self::manifest_path(&self.name))?;
+
+        ok!()
+    }
+
+    ///Имя карты становится именем файла манифеста, поэтому допускаются только латинские буквы, цифры, '_' и '-'
+    pub fn check_name(name:&str) -> Result<(),Error> {
+        let is_allowed=|c:char| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+            _ => false,
+        };
+
+        if name.is_empty() || !name.chars().all(is_allowed) {
+            return err!(Error::BadMapName, name.to_string());
+        }
+
+        ok!()
+    }
+
+    ///Снимок карты под именем name, сохраняемый вместо самой карты, которая продолжает работать под прежним именем
+    pub fn snapshot(&self, name:String) -> Map {
+        Map {
+            name,
+            storage:self.storage,
+            resources:self.resources.clone(),
+            creating:VecDeque::new(),
+        }
+    }
+
+    ///Запоминает данные ресурса, отправленного в Storage на создание
+    pub fn expect_resource(&mut self, data:Vec<u8>) {
+        self.creating.push_back(data);
+    }
+
+    ///Storage создал ресурс, его данные - данные самого раннего ресурса, отправленного на создание
+    pub fn add_resource(&mut self, resource_id:ResourceID) {
+        let data=self.creating.pop_front();
+        self.resources.push((resource_id, data));
+    }
+
+    ///Запоминает данные ресурса, возвращает false, если ресурс не принадлежит карте
+    pub fn set_resource_data(&mut self, resource_id:&ResourceID, data:Vec<u8>) -> bool {
+        for &mut (ref map_resource_id, ref mut resource_data) in self.resources.iter_mut() {
+            if map_resource_id.code()==resource_id.code() {
+                *resource_data=Some(data);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    ///Ресурсы карты, данные которых уже есть у Handler
+    pub fn loaded_resources(&self) -> Vec<(ResourceID,&[u8])> {
+        self.resources.iter().filter_map(|&(ref resource_id,ref data)| {
+            data.as_ref().map(|data| (resource_id.clone(),&data[..]))
+        }).collect()
+    }
+
+    pub fn has_resource(&self, resource_id:&ResourceID) -> bool {
+        self.resources.iter().any(|&(ref map_resource_id,_)| map_resource_id.code()==resource_id.code())
+    }
+
+    pub fn resource_ids(&self) -> Vec<ResourceID> {
+        self.resources.iter().map(|&(ref resource_id,_)| resource_id.clone()).collect()
+    }
+
+    ///Все ли ресурсы получены от Storage
+    pub fn is_loaded(&self) -> bool {
+        self.resources.iter().all(|&(_,ref data)| data.is_some())
+    }
+
+    fn manifest_path(name:&str) -> PathBuf {
+        let mut path=PathBuf::from(MAPS_DIRECTORY);
+        path.push(format!("{}.map", name));
+
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Map;
+
+    #[test]
+    fn map_name_is_plain_file_stem() {
+        for name in ["world", "World_2", "test-map"].iter() {
+            assert!(Map::check_name(name).is_ok(), "{} is rejected", name);
+        }
+
+        for name in ["", "../world", "maps/world", "world.map", "мир", "a b"].iter() {
+            assert!(Map::check_name(name).is_err(), "{} is accepted", name);
+        }
+    }
+}
@@ -6,5 +6,11 @@ pub use self::error::Error;
 pub mod commands;
 pub use self::commands::{HandlerCommand,SenderCommand};
 
+pub mod map;
+pub use self::map::Map;
+
+pub mod game_loop;
+pub use self::game_loop::GameLoop;
+
 pub mod handler;
 pub use self::handler::{Handler,HandlerReceiver,HandlerSender};
\ No newline at end of file
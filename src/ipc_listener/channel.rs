@@ -0,0 +1,139 @@
+//!Канал команд IpcListener-а. IpcListener ждёт одновременно сокет и канал команд, но nanomsg не умеет ждать mpsc канал,
+//!поэтому каждая отправленная команда будит его байтом в inproc сокет Waker.
+
+use std;
+use nanomsg;
+
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize,ATOMIC_USIZE_INIT,Ordering};
+
+use super::IpcListenerCommand;
+
+///Адреса Waker-ов уникальны, потому что при перезапуске сервера старый IpcListener мог ещё не закрыть свой
+static NEXT_WAKER:AtomicUsize=ATOMIC_USIZE_INIT;
+
+pub type IpcListenerReceiver = mpsc::Receiver<IpcListenerCommand>;
+
+///Отправляет команды IpcListener-у и будит его
+#[derive(Clone)]
+pub struct IpcListenerSender {
+    sender:mpsc::Sender<IpcListenerCommand>,
+    waker:Arc<Mutex<(nanomsg::Socket,nanomsg::Endpoint)>>,
+}
+
+///Сокет, который становится доступным для чтения, когда в канале есть команды
+pub struct Waker {
+    pub socket:nanomsg::Socket,
+    endpoint:nanomsg::Endpoint,
+}
+
+///Создаёт канал команд IpcListener-а
+pub fn channel() -> Result<(IpcListenerSender,IpcListenerReceiver,Waker),nanomsg::result::Error> {
+    let address=format!("inproc://handler.ipc_listener.waker.{}", NEXT_WAKER.fetch_add(1, Ordering::SeqCst));
+
+    let mut waker_socket=nanomsg::Socket::new(nanomsg::Protocol::Pull)?;
+    let waker_endpoint=waker_socket.bind(address.as_str())?;
+
+    let mut wake_socket=nanomsg::Socket::new(nanomsg::Protocol::Push)?;
+    let wake_endpoint=wake_socket.connect(address.as_str())?;
+
+    let (sender, receiver) = mpsc::channel();
+
+    let ipc_listener_sender=IpcListenerSender{
+        sender,
+        waker:Arc::new(Mutex::new((wake_socket,wake_endpoint))),
+    };
+
+    let waker=Waker{
+        socket:waker_socket,
+        endpoint:waker_endpoint,
+    };
+
+    Ok((ipc_listener_sender, receiver, waker))
+}
+
+impl IpcListenerSender {
+    pub fn send(&self, command:IpcListenerCommand) -> Result<(),mpsc::SendError<IpcListenerCommand>> {
+        self.sender.send(command)?;
+
+        //Если буфер Waker-а полон, IpcListener и так проснётся, а потерянное пробуждение подберёт heartbeat
+        match self.waker.lock() {
+            Ok(mut waker) => match waker.0.nb_write(&[0]) {
+                Ok(_) | Err(nanomsg::result::Error::TryAgain) => {},
+                Err(error) => warn!("Can not wake IpcListener: {}", error),
+            },
+            Err(_) => {},
+        }
+
+        Ok(())
+    }
+}
+
+impl Waker {
+    ///Вычитывает все пробуждения
+    pub fn drain(&mut self) -> Result<(),nanomsg::result::Error> {
+        let mut buffer=Vec::with_capacity(1);
+
+        loop {
+            match self.socket.nb_read_to_end(&mut buffer) {
+                Ok(_) => buffer.clear(),
+                Err(nanomsg::result::Error::TryAgain) => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        self.endpoint.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Доступен ли Waker для чтения в течение timeout миллисекунд
+    fn is_woken(waker:&mut Waker, timeout:isize) -> bool {
+        let mut poll_fds=[waker.socket.new_pollfd(nanomsg::PollInOut::In)];
+        let mut poll_request=nanomsg::PollRequest::new(&mut poll_fds);
+
+        match nanomsg::Socket::poll(&mut poll_request, timeout) {
+            Ok(_) => poll_request.get_fds()[0].can_read(),
+            Err(nanomsg::result::Error::Timeout) => false,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    #[test]
+    fn command_wakes_listener() {
+        let (sender, receiver, mut waker) = channel().unwrap();
+        assert!(!is_woken(&mut waker, 0));
+
+        sender.send(IpcListenerCommand::Shutdown).unwrap();
+        assert!(is_woken(&mut waker, 1000));
+
+        match receiver.try_recv() {
+            Ok(IpcListenerCommand::Shutdown) => {},
+            _ => panic!("Command is lost"),
+        }
+
+        waker.drain().unwrap();
+        assert!(!is_woken(&mut waker, 0));
+    }
+
+    #[test]
+    fn full_waker_does_not_lose_commands() {
+        let (sender, receiver, mut waker) = channel().unwrap();
+
+        for _ in 0..10_000 {
+            sender.send(IpcListenerCommand::HandlerIsReady).unwrap();
+        }
+
+        assert!(is_woken(&mut waker, 1000));
+        waker.drain().unwrap();
+        assert_eq!(receiver.try_iter().count(), 10_000);
+    }
+}
@@ -1,6 +1,7 @@
 use handler;
 
 use ::ThreadSource;
+use automat::Phase;
 
 use handler::HandlerSender;
 use ::ArcTasksQueue;
@@ -21,8 +22,10 @@ pub enum IpcListenerCommand {
     Shutdown,
     HandlerFinished,
 
-    GenerateMap,
-    //LoadMap,
-    CloseMap,
+    ///Стадии карты, готовность возвращается с фазой барьера Автомата
+    GenerateMap(Phase),
+    LoadMap(Phase),
+    SaveMap(Phase),
+    CloseMap(Phase),
     //Play
 }
\ No newline at end of file
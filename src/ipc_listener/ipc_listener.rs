@@ -1,6 +1,7 @@
 use std;
 use nanomsg;
 use automat;
+use tasks_queue;
 use nes::{ErrorInfo,ErrorInfoTrait};
 use common_messages;
 
@@ -19,7 +20,7 @@ use common_messages::{HandlerToHandler,StorageToHandler};
 use automat::{AutomatCommand,AutomatSignal};
 
 use ::ArcProperties;
-use ::ArcTasksQueue;
+use ::{Task, ArcTasksQueue};
 use ::ArcSender;
 use ::ArcAutomat;
 use ::ThreadSource;
@@ -29,14 +30,14 @@ use ::ServerID;
 use ::ResourceID;
 
 const BUFFER_SIZE:usize = 32*1024;
-const READ_TIMEOUT:isize = 50;
-//const RECV_IPC_LISTENER_RECEIVER_INTERVAL:Duration=Duration::new(1,0); //TODO const fn
+///Раз в HEARTBEAT_INTERVAL секунд IpcListener проверяет канал команд, даже если его не будили
+const HEARTBEAT_INTERVAL:u64 = 1;
+///Сколько сообщений читается за одно пробуждение, чтобы поток сообщений не задерживал команды и heartbeat
+const MESSAGES_PER_POLL:usize = 32;
 
 use super::Error;
 use super::IpcListenerCommand;
-
-pub type IpcListenerSender = std::sync::mpsc::Sender<IpcListenerCommand>;
-pub type IpcListenerReceiver = std::sync::mpsc::Receiver<IpcListenerCommand>;
+use super::{IpcListenerSender,IpcListenerReceiver,Waker};
 
 pub struct IpcListener {
     ipc_listener_receiver:IpcListenerReceiver,
@@ -44,14 +45,21 @@ pub struct IpcListener {
     tasks_queue:ArcTasksQueue,
     sender:ArcSender,
     automat:ArcAutomat,
+    ///Handler сам выполняет задачи, его нужно будить
+    wake_handler:bool,
 
     socket:nanomsg::Socket,
     endpoint:nanomsg::Endpoint,
+    ///Будит IpcListener, когда в канале появляются команды
+    waker:Waker,
 }
 
 impl IpcListener {
     pub fn start(properties: ArcProperties) -> (JoinHandle<()>,IpcListenerSender) {
-        let (ipc_listener_sender, ipc_listener_receiver) = std::sync::mpsc::channel();
+        let (ipc_listener_sender, ipc_listener_receiver, waker) = match super::channel::channel() {
+            Ok(channel) => channel,
+            Err(error) => panic!("Can not create IpcListener channel: {}", error),
+        };
 
         let join_handle=std::thread::Builder::new().name("Handler.IpcListener".to_string()).spawn(move|| {
             let handler_sender = match ipc_listener_receiver.recv() {
@@ -77,6 +85,7 @@ impl IpcListener {
 
             let mut ipc_listener = match IpcListener::setup(
                 ipc_listener_receiver,
+                waker,
                 handler_sender.clone(),
                 tasks_queue,
                 sender,
@@ -104,6 +113,7 @@ impl IpcListener {
                 },
                 Err(error) => {
                     error!("IpcListener Error: {}",error);
+                    ipc_listener.automat.log_history();
 
                     match error {
                         Error::NanomsgError(_,_) => {
@@ -111,7 +121,10 @@ impl IpcListener {
                             //NOTE:Close the socket?!
                         },
                         Error::HandlerThreadCrash(_,source) => {
-                            //TODO:try to save world
+                            //Мир восстанавливается из журнала после перезапуска, поэтому журнал нужно только сбросить на диск
+                            if let Err(error)=ipc_listener.tasks_queue.sync_journal() {
+                                error!("Can not sync journal: {}", error);
+                            }
                         },
                         Error::BalancerCrash(_,source) => {},
                         Error::BalancerCrashed(_,e) => {
@@ -134,6 +147,7 @@ impl IpcListener {
 
     fn setup(
         ipc_listener_receiver:IpcListenerReceiver,
+        waker:Waker,
         handler_sender:HandlerSender,
         tasks_queue:ArcTasksQueue,
         sender:ArcSender,
@@ -141,7 +155,6 @@ impl IpcListener {
         properties: ArcProperties,
     ) -> Result<Self,Error> {
         let mut socket = try!(nanomsg::Socket::new(nanomsg::Protocol::Pull),Error::NanomsgError);
-        socket.set_receive_timeout(READ_TIMEOUT);
         let mut endpoint = try!(socket.bind(properties.argument.ipc_listener_address.to_string().as_str()),Error::NanomsgError);
 
         let ipc_listener = IpcListener{
@@ -150,9 +163,11 @@ impl IpcListener {
             tasks_queue,
             sender,
             automat,
+            wake_handler:properties.workers.count==0,
 
             socket,
-            endpoint
+            endpoint,
+            waker,
         };
 
         ok!( ipc_listener )
@@ -175,79 +190,124 @@ impl IpcListener {
         ok!()
     }
 
-    fn lifecycle_listen(&mut self) -> Result<(),Error> {//Finishes without error only if Shutdown signal has been received.
-        use std::time::SystemTime;
-        use std::io::Read;
-        let mut buffer=Vec::with_capacity(BUFFER_SIZE);
+    ///Ждёт одновременно сообщения серверов и команды, завершается без ошибки, только если получена команда Shutdown
+    fn lifecycle_listen(&mut self) -> Result<(),Error> {
+        use std::time::Instant;
 
-        //let mut recv_ipc_listener_receiver_time=SystemTime::now()+RECV_IPC_LISTENER_RECEIVER_INTERVAL;//TODO const fn
-        let mut recv_ipc_listener_receiver_time=SystemTime::now();
+        let mut heartbeat_time=Instant::now()+Duration::new(HEARTBEAT_INTERVAL,0);
 
         loop {
-            let now=SystemTime::now();
-
-            //Read channel each RECV_IPC_LISTENER_RECEIVER_INTERVAL
-            if now > recv_ipc_listener_receiver_time {
-                loop{
-                    let command = match self.ipc_listener_receiver.try_recv() {
-                        Ok(command) => command,
-                        Err(std::sync::mpsc::TryRecvError::Empty) => break,
-                        Err(std::sync::mpsc::TryRecvError::Disconnected) =>
-                            return err!(Error::HandlerThreadCrash, ThreadSource::Handler),
-                    };
-
-                    match command {
-                        IpcListenerCommand::HandlerThreadCrash(source) => return err!(Error::HandlerThreadCrash, source),
-                        IpcListenerCommand::BalancerCrash(source) => return err!(Error::BalancerCrash, source),
-
-                        IpcListenerCommand::Shutdown => return ok!(),
-
-                        IpcListenerCommand::GenerateMap =>
-                            channel_send!(self.handler_sender, HandlerCommand::AutomatSignal(AutomatSignal::ThreadIsReady(ThreadSource::IpcListener))),
-                        IpcListenerCommand::CloseMap =>
-                            channel_send!(self.handler_sender, HandlerCommand::AutomatSignal(AutomatSignal::ThreadIsReady(ThreadSource::IpcListener))),
-                        _ => warn!("Unexpected type of IpcListenerCommand"),
-                    }
+            let now=Instant::now();
+
+            if now >= heartbeat_time {
+                //Подбирает команды, если пробуждение было потеряно
+                if self.handle_commands()? {
+                    return ok!();
                 }
 
-                //recv_ipc_listener_receiver_time=now+RECV_IPC_LISTENER_RECEIVER_INTERVAL;
-                recv_ipc_listener_receiver_time=now+Duration::new(1,0);
-                channel_send!(self.handler_sender, HandlerCommand::EachSecond);
+                heartbeat_time=now+Duration::new(HEARTBEAT_INTERVAL,0);
+                continue;
             }
 
-            //Read IPC
-            match self.socket.read_to_end(&mut buffer) {
-                Ok(length) => {
-                    let (read_length,connection_id,time,number,message_type) = common_messages::read_header( &buffer[..] );
-                    assert_eq!(read_length as usize,length);
+            let timeout=heartbeat_time-now;
+            let timeout=timeout.as_secs() as isize*1000 + (timeout.subsec_nanos()/1_000_000) as isize + 1;
 
-                    match message_type {
-                        common_messages::Type::BalancerToHandler => {
-                            let message = common_messages::read_message( &buffer[..] );
+            let mut poll_fds=[
+                self.socket.new_pollfd(nanomsg::PollInOut::In),
+                self.waker.socket.new_pollfd(nanomsg::PollInOut::In),
+            ];
 
-                            self.handle_balancer_message(connection_id,time,number,message)?
-                        },
-                        common_messages::Type::StorageToHandler => {
-                            let message = common_messages::read_message( &buffer[..] );
-                            self.handle_storage_message(connection_id,time,number,message)?;
-                        },
-                        common_messages::Type::HandlerToHandler => {
-                            let message = common_messages::read_message( &buffer[..] );
-                            self.handle_handler_message(connection_id,time,number,message)?;
-                        },
-                        _ =>
-                            panic!("Unexpected type of message {:?}", message_type),
-                    }
-                },
-                Err(e) => {
-                    if e.kind() != std::io::ErrorKind::TimedOut {
-                        return err!(Error::IOError,Box::new(e));
-                    }
+            let (socket_is_readable, waker_is_readable)={
+                let mut poll_request=nanomsg::PollRequest::new(&mut poll_fds);
+
+                match nanomsg::Socket::poll(&mut poll_request, timeout) {
+                    Ok(_) => {},
+                    Err(nanomsg::result::Error::Timeout) | Err(nanomsg::result::Error::Interrupted) => continue,
+                    Err(error) => return err!(Error::NanomsgError, Box::new(error)),
                 }
+
+                let poll_fds=poll_request.get_fds();
+                (poll_fds[0].can_read(), poll_fds[1].can_read())
+            };
+
+            if waker_is_readable {
+                try!(self.waker.drain(), Error::NanomsgError);
+
+                if self.handle_commands()? {
+                    return ok!();
+                }
+            }
+
+            if socket_is_readable {
+                self.handle_messages()?;
+            }
+        }
+    }
+
+    ///Выполняет все команды из канала, возвращает true, если получена команда Shutdown
+    fn handle_commands(&mut self) -> Result<bool,Error> {
+        loop {
+            let command = match self.ipc_listener_receiver.try_recv() {
+                Ok(command) => command,
+                Err(std::sync::mpsc::TryRecvError::Empty) => return ok!(false),
+                Err(std::sync::mpsc::TryRecvError::Disconnected) =>
+                    return err!(Error::HandlerThreadCrash, ThreadSource::Handler),
+            };
+
+            match command {
+                IpcListenerCommand::HandlerThreadCrash(source) => return err!(Error::HandlerThreadCrash, source),
+                IpcListenerCommand::BalancerCrash(source) => return err!(Error::BalancerCrash, source),
+
+                IpcListenerCommand::Shutdown => return ok!(true),
+
+                IpcListenerCommand::GenerateMap(phase) =>
+                    channel_send!(self.handler_sender, HandlerCommand::AutomatSignal(AutomatSignal::ThreadIsReady(ThreadSource::IpcListener, phase))),
+                IpcListenerCommand::LoadMap(phase) =>
+                    channel_send!(self.handler_sender, HandlerCommand::AutomatSignal(AutomatSignal::ThreadIsReady(ThreadSource::IpcListener, phase))),
+                IpcListenerCommand::SaveMap(phase) =>
+                    channel_send!(self.handler_sender, HandlerCommand::AutomatSignal(AutomatSignal::ThreadIsReady(ThreadSource::IpcListener, phase))),
+                IpcListenerCommand::CloseMap(phase) =>
+                    channel_send!(self.handler_sender, HandlerCommand::AutomatSignal(AutomatSignal::ThreadIsReady(ThreadSource::IpcListener, phase))),
+                _ => warn!("Unexpected type of IpcListenerCommand"),
+            }
+        }
+    }
+
+    ///Читает все сообщения, пришедшие в сокет
+    fn handle_messages(&mut self) -> Result<(),Error> {
+        let mut buffer=Vec::with_capacity(BUFFER_SIZE);
+
+        for _ in 0..MESSAGES_PER_POLL {
+            let length=match self.socket.nb_read_to_end(&mut buffer) {
+                Ok(length) => length,
+                Err(nanomsg::result::Error::TryAgain) => return ok!(),
+                Err(error) => return err!(Error::NanomsgError, Box::new(error)),
+            };
+
+            let (read_length,connection_id,time,number,message_type) = common_messages::read_header( &buffer[..] );
+            assert_eq!(read_length as usize,length);
+
+            match message_type {
+                common_messages::Type::BalancerToHandler => {
+                    let message = common_messages::read_message( &buffer[..] );
+                    self.handle_balancer_message(connection_id,time,number,message)?;
+                },
+                common_messages::Type::StorageToHandler => {
+                    let message = common_messages::read_message( &buffer[..] );
+                    self.handle_storage_message(connection_id,time,number,message)?;
+                },
+                common_messages::Type::HandlerToHandler => {
+                    let message = common_messages::read_message( &buffer[..] );
+                    self.handle_handler_message(connection_id,time,number,message)?;
+                },
+                _ =>
+                    panic!("Unexpected type of message {:?}", message_type),
             }
 
             buffer.clear();
         }
+
+        ok!()//Оставшиеся сообщения прочитаются после следующего poll
     }
 
     fn lifecycle_shutdown(&mut self) -> Result<(),Error> {
@@ -272,16 +332,30 @@ impl IpcListener {
                 let command=HandlerCommand::AutomatSignal(AutomatSignal::Familiarize(Box::new(familiarity_lists)));
                 channel_send!(self.handler_sender, command );
             },
+            #[cfg(feature="hot_join")]
+            BalancerToHandler::HotFamiliarity{storages,handlers} => {
+                let familiarity_lists=sender::FamiliarityLists::new(storages,handlers);
+                let command=HandlerCommand::AutomatSignal(AutomatSignal::HotJoin(Box::new(familiarity_lists)));
+                channel_send!(self.handler_sender, command );
+            },
             BalancerToHandler::Shutdown(restart) => {
                 let restart=if restart==0 {false} else {true};
                 channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::Shutdown(restart)) );
             },
             BalancerToHandler::GenerateMap(map_name) =>
                 channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::GenerateMap(map_name)) ),
+            BalancerToHandler::LoadMap(map_name) =>
+                channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::LoadMap(map_name)) ),
+            BalancerToHandler::SaveMap(map_name) =>
+                channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::SaveMap(map_name)) ),
             BalancerToHandler::CloseMap =>
                 channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::CloseMap) ),
+            BalancerToHandler::CancelPending =>
+                channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::CancelPending) ),
+            BalancerToHandler::Freeze =>
+                channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::Freeze) ),
             BalancerToHandler::Defrost =>
-                info!("defrost"),
+                channel_send!(self.handler_sender, HandlerCommand::AutomatCommand(AutomatCommand::Defrost) ),
             _ => unimplemented!(),
         }
 
@@ -296,18 +370,32 @@ impl IpcListener {
                 channel_send!(self.handler_sender, HandlerCommand::ConnectionAccepted(ServerType::Storage, connection_id, set_connection_id.into())),
             StorageToHandler::Connected =>
                 channel_send!(self.handler_sender, HandlerCommand::Connected(ServerType::Storage, connection_id)),
-            StorageToHandler::ResourceCreated(resource_id_code) => {
-                let resource_id=ResourceID::from(resource_id_code);
-                info!("Created {}",resource_id);
+            #[cfg(feature="farewell")]
+            StorageToHandler::Goodbye =>
+                channel_send!(self.handler_sender, HandlerCommand::Goodbye(ServerType::Storage, connection_id)),
+            #[cfg(feature="farewell")]
+            StorageToHandler::GoodbyeAccepted =>
+                channel_send!(self.handler_sender, HandlerCommand::GoodbyeAccepted(ServerType::Storage, connection_id)),
+            StorageToHandler::ResourceCreated(resource_id_code) =>
+                self.push_map_task(Task::ResourceCreated(ResourceID::from(resource_id_code)))?,
+            StorageToHandler::Resource(resource_id_code, data) =>
+                self.push_map_task(Task::Resource(ResourceID::from(resource_id_code), data))?,
+            StorageToHandler::ResourceNotFound(resource_id_code) =>
+                channel_send!(self.handler_sender, HandlerCommand::ResourceNotFound(ResourceID::from(resource_id_code))),
+            StorageToHandler::ResourceSaved(resource_id_code) =>
+                channel_send!(self.handler_sender, HandlerCommand::ResourceSaved(ResourceID::from(resource_id_code))),
+        }
 
-                use common_sender::StorageTrait;
-                //let message=::common_messages::HandlerToStorage::DeleteResource(resource_id.code());
-                //self.sender.storages.send(ConnectionID::new(0,1),0,&message).unwrap();
-            },
-            StorageToHandler::Resource(resource_id_code, data) => {
-                let resource_id=ResourceID::from(resource_id_code);
-                info!("Resource {}",resource_id);
-            }
+        ok!()
+    }
+
+    ///Кладёт задачу текущей карты в очередь и, если Handler сам выполняет задачи, будит его.
+    ///Ответы Storage не отбрасываются, даже если очередь заполнена
+    fn push_map_task(&mut self, task:Task) -> Result<(),Error> {
+        do_tasks_queue_transaction!(self.tasks_queue.push_map_task(task));
+
+        if self.wake_handler {
+            channel_send!(self.handler_sender, HandlerCommand::Task);
         }
 
         ok!()
@@ -321,6 +409,12 @@ impl IpcListener {
                 channel_send!(self.handler_sender, HandlerCommand::ConnectionAccepted(ServerType::Handler, connection_id, set_connection_id.into())),
             HandlerToHandler::Connected =>
                 channel_send!(self.handler_sender, HandlerCommand::Connected(ServerType::Handler, connection_id)),
+            #[cfg(feature="farewell")]
+            HandlerToHandler::Goodbye =>
+                channel_send!(self.handler_sender, HandlerCommand::Goodbye(ServerType::Handler, connection_id)),
+            #[cfg(feature="farewell")]
+            HandlerToHandler::GoodbyeAccepted =>
+                channel_send!(self.handler_sender, HandlerCommand::GoodbyeAccepted(ServerType::Handler, connection_id)),
         }
 
         ok!()
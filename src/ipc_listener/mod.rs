@@ -5,5 +5,8 @@ pub use self::error::Error;
 pub mod commands;
 pub use self::commands::IpcListenerCommand;
 
+pub mod channel;
+pub use self::channel::{IpcListenerSender,IpcListenerReceiver,Waker};
+
 pub mod ipc_listener;
-pub use self::ipc_listener::{IpcListener,IpcListenerReceiver,IpcListenerSender};
\ No newline at end of file
+pub use self::ipc_listener::IpcListener;
\ No newline at end of file
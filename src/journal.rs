@@ -0,0 +1,595 @@
+//!Журнал принятых и ещё не выполненных задач. Журнал хранится на диске и только дописывается,
+//!поэтому задачи переживают падение процесса. При открытии журнала незавершённые задачи
+//!переписываются в новый журнал, который атомарно заменяет старый, и возвращаются вместе с новыми номерами.
+//!Кроме задач записывается стадия карты, которую выполняет Handler(MapStage), и задачи её графа вместе с зависимостями,
+//!так что после перезапуска Handler повторяет стадию и достраивает граф из невыполненных задач.
+//!Задачи графа без незавершённой стадии отбрасываются, служебные задачи не записываются, тк они относятся к прерванному запуску.
+
+use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+
+use std::io::{Read,Write};
+use std::fs::{File,OpenOptions};
+use std::collections::BTreeMap;
+
+use task::{Task,MapGenerationStep};
+use task_graph::TaskNode;
+
+use ::ResourceID;
+use ::JournalProperties;
+
+const RECORD_ACCEPTED:u8=1;
+const RECORD_COMPLETED:u8=2;
+const RECORD_STAGE:u8=3;
+const RECORD_GRAPH_NODE:u8=4;
+
+const STAGE_GENERATE:u8=0;
+const STAGE_LOAD:u8=1;
+const STAGE_SAVE:u8=2;
+
+const TASK_RESOURCE_CREATED:u8=0;
+const TASK_RESOURCE:u8=1;
+const TASK_GENERATE_MAP:u8=2;
+
+///Журнал задач
+pub struct Journal {
+    file:File,
+    next_id:u64,
+}
+
+///Стадия карты, которую выполняет Handler
+#[derive(Debug,Clone,Eq,PartialEq)]
+pub enum MapStage {
+    Generate(String),
+    Load(String),
+    ///Сохранение карты(первое имя) под новым именем, после перезапуска карта сначала загружается
+    Save(String,String),
+}
+
+///Незавершённая работа из журнала с новыми номерами
+pub struct Recovery {
+    ///Задачи вне графов
+    pub tasks:Vec<(u64,Task)>,
+    pub stage:Option<RecoveredStage>,
+}
+
+///Незавершённая стадия карты и невыполненные задачи её графа
+pub struct RecoveredStage {
+    pub id:u64,
+    pub stage:MapStage,
+    pub graph_nodes:Vec<RecoveredNode>,
+}
+
+///Невыполненная задача графа, узлы и зависимости пронумерованы так же, как в исходном графе
+pub struct RecoveredNode {
+    pub id:u64,
+    pub node:TaskNode,
+    pub dependencies:Vec<TaskNode>,
+    pub task:Task,
+}
+
+///Запись журнала, ещё не отмеченная выполненной
+enum Pending {
+    Task(Task),
+    Stage(MapStage),
+    GraphNode(u64,TaskNode,Vec<TaskNode>,Task),
+}
+
+define_error!( Error,
+    IOError(io_error:Box<std::io::Error>) =>
+        "IO Error: {1}",
+    Corrupted(message:String) =>
+        "Journal is corrupted: {1}"
+);
+
+impl_from_error!(std::io::Error => Error::IOError);
+
+impl Journal {
+    ///Открывает журнал, если он включён, и возвращает работу, которая не была выполнена, с новыми номерами в журнале
+    pub fn open(properties:&JournalProperties) -> Result<(Option<Journal>,Recovery),Error> {
+        let path=match properties.path {
+            Some(ref path) => path,
+            None => return ok!((None,Recovery{tasks:Vec::new(), stage:None})),
+        };
+
+        let pending=match File::open(path) {
+            Ok(mut file) => {
+                let mut content=Vec::new();
+                file.read_to_end(&mut content)?;
+
+                read_pending(&content[..])?
+            },
+            Err(ref error) if error.kind()==std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return err!(Error::IOError, Box::new(error)),
+        };
+
+        //Старый журнал заменяется только после того, как новый записан на диск, поэтому падение во время
+        //открытия не теряет задачи
+        let temp_path=format!("{}.tmp", path);
+
+        let mut journal=Journal{
+            file:OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?,
+            next_id:0,
+        };
+
+        let recovery=journal.rewrite(pending)?;
+
+        journal.sync()?;
+        std::fs::rename(&temp_path, path)?;
+
+        //После переименования файл остаётся тем же, дальше в него только дописываются записи
+        journal.file=OpenOptions::new().append(true).open(path)?;
+
+        ok!((Some(journal),recovery))
+    }
+
+    ///Записывает незавершённую работу под новыми номерами. Стадии выполняются по одной,
+    ///поэтому незавершённой может остаться только последняя, более ранние прерваны вместе с процессом
+    fn rewrite(&mut self, pending:BTreeMap<u64,Pending>) -> Result<Recovery,Error> {
+        let stage_id=pending.iter().filter_map(|(&id, record)| match *record {
+            Pending::Stage(_) => Some(id),
+            _ => None,
+        }).last();
+
+        let mut recovery=Recovery{
+            tasks:Vec::new(),
+            stage:None,
+        };
+
+        for (id, record) in pending {
+            match record {
+                Pending::Task(task) => {
+                    let new_id=self.accept(&task)?;
+                    recovery.tasks.push((new_id,task));
+                },
+                Pending::Stage(stage) => {
+                    if Some(id)==stage_id {
+                        recovery.stage=Some(RecoveredStage{
+                            id:self.accept_stage(&stage)?,
+                            stage,
+                            graph_nodes:Vec::new(),
+                        });
+                    }
+                },
+                //Узлы записываются после своей стадии, поэтому она уже переписана
+                Pending::GraphNode(node_stage_id, node, dependencies, task) => {
+                    if Some(node_stage_id)!=stage_id {
+                        continue;
+                    }
+
+                    match recovery.stage {
+                        Some(ref mut recovered_stage) => {
+                            let new_id=self.accept_graph_node(recovered_stage.id, node, &dependencies[..], &task)?;
+                            recovered_stage.graph_nodes.push(RecoveredNode{id:new_id, node, dependencies, task});
+                        },
+                        None => {},
+                    }
+                },
+            }
+        }
+
+        ok!(recovery)
+    }
+
+    ///Записывает принятую задачу, возвращает её номер в журнале
+    pub fn accept(&mut self, task:&Task) -> Result<u64,Error> {
+        let (id, mut record)=self.record(RECORD_ACCEPTED);
+        write_task(&mut record, task);
+
+        self.file.write_all(&record[..])?;
+
+        ok!(id)
+    }
+
+    ///Записывает начатую стадию карты, возвращает её номер в журнале
+    pub fn accept_stage(&mut self, stage:&MapStage) -> Result<u64,Error> {
+        let (id, mut record)=self.record(RECORD_STAGE);
+        write_stage(&mut record, stage);
+
+        self.file.write_all(&record[..])?;
+
+        ok!(id)
+    }
+
+    ///Записывает задачу графа стадии stage_id вместе с её зависимостями, возвращает её номер в журнале
+    pub fn accept_graph_node(&mut self, stage_id:u64, node:TaskNode, dependencies:&[TaskNode], task:&Task) -> Result<u64,Error> {
+        let (id, mut record)=self.record(RECORD_GRAPH_NODE);
+        write_u64(&mut record, stage_id);
+        write_u64(&mut record, node as u64);
+        write_u64(&mut record, dependencies.len() as u64);
+
+        for &dependency in dependencies.iter() {
+            write_u64(&mut record, dependency as u64);
+        }
+
+        write_task(&mut record, task);
+
+        self.file.write_all(&record[..])?;
+
+        ok!(id)
+    }
+
+    ///Записывает, что задача выполнена или отменена, или что стадия завершена
+    pub fn complete(&mut self, id:u64) -> Result<(),Error> {
+        let mut record=Vec::with_capacity(9);
+        record.push(RECORD_COMPLETED);
+        write_u64(&mut record, id);
+
+        self.file.write_all(&record[..])?;
+
+        ok!()
+    }
+
+    ///Сбрасывает журнал на диск
+    pub fn sync(&mut self) -> Result<(),Error> {
+        self.file.sync_all()?;
+
+        ok!()
+    }
+
+    ///Выдаёт номер новой записи и начинает её
+    fn record(&mut self, record_type:u8) -> (u64,Vec<u8>) {
+        let id=self.next_id;
+        self.next_id+=1;
+
+        let mut record=Vec::with_capacity(64);
+        record.push(record_type);
+        write_u64(&mut record, id);
+
+        (id,record)
+    }
+}
+
+///Последняя запись может быть недописана, если процесс упал во время записи, она пропускается
+fn read_pending(mut content:&[u8]) -> Result<BTreeMap<u64,Pending>,Error> {
+    let mut pending=BTreeMap::new();
+
+    loop {
+        let rest=content;
+
+        let record_type=match read_u8(&mut content) {
+            Some(record_type) => record_type,
+            None => break,
+        };
+
+        let id=match read_u64(&mut content) {
+            Some(id) => id,
+            None => break,
+        };
+
+        let record=match record_type {
+            RECORD_ACCEPTED => read_task(&mut content)?.map(|task| Pending::Task(task)),
+            RECORD_STAGE => read_stage(&mut content)?.map(|stage| Pending::Stage(stage)),
+            RECORD_GRAPH_NODE => read_graph_node(&mut content)?,
+            RECORD_COMPLETED => {
+                pending.remove(&id);
+                continue;
+            },
+            _ => return err!(Error::Corrupted, format!("unknown record type {} at {} bytes before end", record_type, rest.len())),
+        };
+
+        match record {
+            Some(record) => {pending.insert(id, record);},
+            None => break,
+        }
+    }
+
+    ok!(pending)
+}
+
+fn write_u64(buffer:&mut Vec<u8>, value:u64) {
+    for i in 0..8 {
+        buffer.push((value >> (i*8)) as u8);
+    }
+}
+
+fn write_bytes(buffer:&mut Vec<u8>, bytes:&[u8]) {
+    write_u64(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn write_task(buffer:&mut Vec<u8>, task:&Task) {
+    match *task {
+        Task::ResourceCreated(ref resource_id) => {
+            buffer.push(TASK_RESOURCE_CREATED);
+            write_u64(buffer, resource_id.code() as u64);
+        },
+        Task::Resource(ref resource_id, ref data) => {
+            buffer.push(TASK_RESOURCE);
+            write_u64(buffer, resource_id.code() as u64);
+            write_bytes(buffer, &data[..]);
+        },
+        Task::GenerateMap(ref map_name, step) => {
+            buffer.push(TASK_GENERATE_MAP);
+            write_bytes(buffer, map_name.as_bytes());
+            buffer.push(step as u8);
+        },
+        Task::Control(..) => unreachable!("Control tasks are not journaled"),
+    }
+}
+
+fn write_stage(buffer:&mut Vec<u8>, stage:&MapStage) {
+    match *stage {
+        MapStage::Generate(ref map_name) => {
+            buffer.push(STAGE_GENERATE);
+            write_bytes(buffer, map_name.as_bytes());
+        },
+        MapStage::Load(ref map_name) => {
+            buffer.push(STAGE_LOAD);
+            write_bytes(buffer, map_name.as_bytes());
+        },
+        MapStage::Save(ref map_name, ref snapshot_name) => {
+            buffer.push(STAGE_SAVE);
+            write_bytes(buffer, map_name.as_bytes());
+            write_bytes(buffer, snapshot_name.as_bytes());
+        },
+    }
+}
+
+fn read_u8(content:&mut &[u8]) -> Option<u8> {
+    if content.len() < 1 {
+        return None;
+    }
+
+    let value=content[0];
+    *content=&content[1..];
+
+    Some(value)
+}
+
+fn read_u64(content:&mut &[u8]) -> Option<u64> {
+    if content.len() < 8 {
+        return None;
+    }
+
+    let value=(0..8).fold(0u64, |value, i| value | (content[i] as u64) << (i*8));
+    *content=&content[8..];
+
+    Some(value)
+}
+
+fn read_bytes(content:&mut &[u8]) -> Option<Vec<u8>> {
+    let len=match read_u64(content) {
+        Some(len) => len as usize,
+        None => return None,
+    };
+
+    if content.len() < len {
+        return None;
+    }
+
+    let bytes=content[..len].to_vec();
+    *content=&content[len..];
+
+    Some(bytes)
+}
+
+///Возвращает None, если запись недописана
+fn read_task(content:&mut &[u8]) -> Result<Option<Task>,Error> {
+    let task_type=match read_u8(content) {
+        Some(task_type) => task_type,
+        None => return ok!(None),
+    };
+
+    let task=match task_type {
+        TASK_RESOURCE_CREATED => {
+            read_u64(content).map(|code| Task::ResourceCreated(ResourceID::from(code)))
+        },
+        TASK_RESOURCE => {
+            match read_u64(content) {
+                Some(code) => read_bytes(content).map(|data| Task::Resource(ResourceID::from(code), data)),
+                None => None,
+            }
+        },
+        TASK_GENERATE_MAP => {
+            let map_name=match read_string(content)? {
+                Some(map_name) => map_name,
+                None => return ok!(None),
+            };
+
+            match read_u8(content) {
+                Some(step) => Some(Task::GenerateMap(map_name, read_map_generation_step(step)?)),
+                None => None,
+            }
+        },
+        _ => return err!(Error::Corrupted, format!("unknown task type {}", task_type)),
+    };
+
+    ok!(task)
+}
+
+///Возвращает None, если строка недописана
+fn read_string(content:&mut &[u8]) -> Result<Option<String>,Error> {
+    match read_bytes(content) {
+        Some(bytes) => {
+            match String::from_utf8(bytes) {
+                Ok(string) => ok!(Some(string)),
+                Err(error) => err!(Error::Corrupted, format!("{}", error)),
+            }
+        },
+        None => ok!(None),
+    }
+}
+
+///Возвращает None, если запись недописана
+fn read_stage(content:&mut &[u8]) -> Result<Option<MapStage>,Error> {
+    let stage_type=match read_u8(content) {
+        Some(stage_type) => stage_type,
+        None => return ok!(None),
+    };
+
+    let map_name=match read_string(content)? {
+        Some(map_name) => map_name,
+        None => return ok!(None),
+    };
+
+    let stage=match stage_type {
+        STAGE_GENERATE => Some(MapStage::Generate(map_name)),
+        STAGE_LOAD => Some(MapStage::Load(map_name)),
+        STAGE_SAVE => read_string(content)?.map(|snapshot_name| MapStage::Save(map_name, snapshot_name)),
+        _ => return err!(Error::Corrupted, format!("unknown map stage {}", stage_type)),
+    };
+
+    ok!(stage)
+}
+
+///Возвращает None, если запись недописана
+fn read_graph_node(content:&mut &[u8]) -> Result<Option<Pending>,Error> {
+    let (stage_id, node, dependencies_count)=match (read_u64(content), read_u64(content), read_u64(content)) {
+        (Some(stage_id), Some(node), Some(dependencies_count)) => (stage_id, node as TaskNode, dependencies_count),
+        _ => return ok!(None),
+    };
+
+    let mut dependencies=Vec::new();
+
+    for _ in 0..dependencies_count {
+        match read_u64(content) {
+            Some(dependency) => dependencies.push(dependency as TaskNode),
+            None => return ok!(None),
+        }
+    }
+
+    ok!(read_task(content)?.map(|task| Pending::GraphNode(stage_id, node, dependencies, task)))
+}
+
+fn read_map_generation_step(step:u8) -> Result<MapGenerationStep,Error> {
+    let step=match step {
+        0 => MapGenerationStep::Terrain,
+        1 => MapGenerationStep::Regions,
+        2 => MapGenerationStep::Objects,
+        3 => MapGenerationStep::Persistence,
+        _ => return err!(Error::Corrupted, format!("unknown map generation step {}", step)),
+    };
+
+    ok!(step)
+}
+
+#[cfg(test)]
+mod tests {
+    use std;
+
+    use std::sync::mpsc;
+
+    use task::{CancellationToken,MapGenerationStep};
+    use task_graph::TaskGraph;
+    use tasks_queue::TasksQueue;
+    use automat::AutomatSignal;
+
+    use ::TasksQueueProperties;
+
+    use super::*;
+
+    fn properties(name:&str) -> JournalProperties {
+        let path=std::env::temp_dir().join(format!("journal_{}_{}", name, std::process::id()));
+        let _=std::fs::remove_file(&path);
+
+        JournalProperties{
+            path:Some(path.to_string_lossy().into_owned()),
+        }
+    }
+
+    fn open(properties:&JournalProperties) -> (Journal,Recovery) {
+        match Journal::open(properties) {
+            Ok((Some(journal), recovery)) => (journal, recovery),
+            Ok((None, _)) => panic!("Journal is disabled"),
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    const STEPS:[MapGenerationStep;4]=[
+        MapGenerationStep::Terrain,
+        MapGenerationStep::Regions,
+        MapGenerationStep::Objects,
+        MapGenerationStep::Persistence,
+    ];
+
+    ///Процесс падает посреди генерации карты: выполнены первые два шага графа, Storage прислал один ответ
+    fn crash_during_generation(properties:&JournalProperties) {
+        let (mut journal, recovery)=open(properties);
+        assert!(recovery.stage.is_none());
+
+        let stage_id=journal.accept_stage(&MapStage::Generate("a".to_string())).unwrap();
+
+        let node_ids=STEPS.iter().enumerate().map(|(node, &step)| {
+            let dependencies=if node==0 {Vec::new()} else {vec![node-1]};
+            journal.accept_graph_node(stage_id, node, &dependencies[..], &Task::GenerateMap("a".to_string(), step)).unwrap()
+        }).collect::<Vec<u64>>();
+
+        journal.complete(node_ids[0]).unwrap();
+        journal.complete(node_ids[1]).unwrap();
+        journal.accept(&Task::ResourceCreated(ResourceID::from(5))).unwrap();
+    }
+
+    #[test]
+    fn unfinished_stage_is_recovered() {
+        let properties=properties("recovered");
+        crash_during_generation(&properties);
+
+        let (_journal, recovery)=open(&properties);
+        let recovered_stage=recovery.stage.unwrap();
+
+        assert_eq!(recovered_stage.stage, MapStage::Generate("a".to_string()));
+        assert_eq!(recovered_stage.graph_nodes.iter().map(|node| node.node).collect::<Vec<TaskNode>>(), vec![2,3]);
+        assert_eq!(recovered_stage.graph_nodes.iter().map(|node| node.dependencies.clone()).collect::<Vec<Vec<TaskNode>>>(), vec![vec![1],vec![2]]);
+        assert_eq!(recovery.tasks.len(), 1);
+
+        let _=std::fs::remove_file(properties.path.unwrap());
+    }
+
+    #[test]
+    fn recovered_graph_completes_after_restart() {
+        let properties=properties("restart");
+        crash_during_generation(&properties);
+
+        let (journal, recovery)=open(&properties);
+        let recovered_stage=recovery.stage.unwrap();
+
+        let (handler_sender, _handler_receiver) = mpsc::channel();
+        let tasks_queue=TasksQueue::new(&TasksQueueProperties::default(), 1, handler_sender, Some(journal));
+
+        let mut graph=TaskGraph::recover(recovered_stage.graph_nodes, &CancellationToken::new(), None);
+        graph.journal_in(Some(recovered_stage.id));
+        graph.on_complete(AutomatSignal::MapLoadedFromStorage);
+        assert!(tasks_queue.push_graph(graph).unwrap().is_none());
+
+        for (journal_id, task) in recovery.tasks {
+            tasks_queue.replay(journal_id, task).unwrap();
+        }
+
+        let mut executed=Vec::new();
+        let mut signal=None;
+
+        while let Some(entry)=tasks_queue.pop(0).unwrap() {
+            match entry.task {
+                Task::GenerateMap(_, step) => executed.push(step),
+                _ => {},
+            }
+
+            let receipt=entry.receipt();
+            let succeeded=entry.execute().is_ok();
+
+            match tasks_queue.finish(receipt, succeeded).unwrap() {
+                Some(graph_signal) => signal=Some(graph_signal),
+                None => {},
+            }
+        }
+
+        //Выполненные до падения шаги не повторяются
+        assert_eq!(executed, vec![MapGenerationStep::Objects, MapGenerationStep::Persistence]);
+
+        match signal {
+            Some(AutomatSignal::MapLoadedFromStorage) => {},
+            _ => panic!("Recovered graph has not completed"),
+        }
+
+        tasks_queue.journal_complete(Some(recovered_stage.id)).unwrap();
+        tasks_queue.sync_journal().unwrap();
+        drop(tasks_queue);
+
+        let (_journal, recovery)=open(&properties);
+        assert!(recovery.stage.is_none());
+        assert!(recovery.tasks.is_empty());
+
+        let _=std::fs::remove_file(properties.path.unwrap());
+    }
+}
@@ -24,23 +24,37 @@ pub use common_types::{ServerType,ServerID,ConnectionID};
 pub use common_types::{ResourceType,ResourceID};
 
 pub mod properties;
-pub use properties::{Argument,Properties,ArcProperties};
+pub use properties::{Argument,Properties,ArcProperties,TasksQueueProperties,WorkersProperties,JournalProperties,AutomatProperties,GameLoopProperties};
 
 #[macro_use]
 pub mod automat;
 pub use self::automat::{Automat,ArcAutomat};
 
+pub mod task;
+pub use self::task::{Task,ControlTask,TaskPriority,TaskKey,TaskOutput};
+
+pub mod journal;
+pub use self::journal::{Journal,MapStage,Recovery,RecoveredStage,RecoveredNode};
+
+pub mod task_graph;
+pub use self::task_graph::TaskGraph;
+
+#[macro_use]
+pub mod tasks_queue;
+pub use self::tasks_queue::{TasksQueue,ArcTasksQueue};
+
+#[macro_use]
+pub mod timer;
+pub use self::timer::{Timer,ArcTimer,TimerEvent,TimerHandle};
+
 pub mod ipc_listener;
 pub use self::ipc_listener::IpcListener;
 //pub use
 pub mod handler;
 pub use self::handler::Handler;
 
-pub mod task;
-pub use self::task::Task;
-
-pub mod tasks_queue;
-pub use self::tasks_queue::{TasksQueue,ArcTasksQueue};
+pub mod worker_pool;
+pub use self::worker_pool::WorkerPool;
 
 pub mod sender;
 pub use self::sender::{Sender,ArcSender};
@@ -61,6 +75,18 @@ impl std::fmt::Display for ThreadSource{
 }
 
 fn main() {
+    //handler dot - выводит Автомат в формате Graphviz и завершается
+    match std::env::args().nth(1) {
+        Some(ref subcommand) if subcommand=="dot" => {
+            if let Err(error)=automat::transitions::write_dot(&mut std::io::stdout()) {
+                panic!("Can not write Automat: {}",error);
+            }
+
+            return;
+        },
+        _ => {}
+    }
+
     let argument = match Argument::read() {
         Ok( properties ) => properties,
         Err( e ) => panic!("Can not read argument: {}",e),
@@ -81,10 +107,25 @@ fn main() {
 
     info!("Hello");
 
-    let (ipc_listener_join_handler,ipc_listener_sender) = IpcListener::start(properties.clone());
-    let handler_join_handler = Handler::start(ipc_listener_sender,properties);
+    //Shutdown(true) перезапускает сервер: потоки, Sender и Автомат создаются заново,
+    //сервер снова регистрируется у Balancer-а с тем же ServerID
+    loop {
+        let (ipc_listener_join_handler,ipc_listener_sender) = IpcListener::start(properties.clone());
+        let handler_join_handler = Handler::start(ipc_listener_sender,properties.clone());
+
+        let restart = match handler_join_handler.join() {
+            Ok(restart) => restart,
+            Err(_) => false,
+        };
+
+        ipc_listener_join_handler.join();
+
+        if !restart {
+            break;
+        }
+
+        info!("Restarting");
+    }
 
-    handler_join_handler.join();
-    ipc_listener_join_handler.join();
     logger.close();
 }
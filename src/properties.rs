@@ -4,6 +4,7 @@ use config;
 use common_address;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use config::read::Config;
 use config::read::Struct;
@@ -13,6 +14,11 @@ use ::ServerID;
 
 pub type ArcProperties = Arc<Properties>;
 
+///Таймаут прощания в секундах, если он не задан в properties.cfg
+const DEFAULT_GOODBYE_TIMEOUT:u64 = 5;
+///Таймаут стадий карты в секундах, если секции automat нет в properties.cfg
+const DEFAULT_STATE_TIMEOUT:u64 = 60;
+
 pub struct Argument {
     pub server_id:ServerID,
     pub connection_id:ConnectionID,
@@ -23,7 +29,58 @@ pub struct Argument {
 }
 
 pub struct Properties {
-    pub argument: Argument
+    pub argument: Argument,
+    pub tasks_queue: TasksQueueProperties,
+    pub workers: WorkersProperties,
+    pub journal: JournalProperties,
+    pub automat: AutomatProperties,
+    pub game_loop: GameLoopProperties,
+}
+
+///Политика выбора полос TasksQueue
+pub struct TasksQueueProperties {
+    ///Сколько раз подряд непустая полоса Normal может быть пропущена, 0 - без защиты от голодания
+    pub normal_max_skips:usize,
+    ///Сколько раз подряд непустая полоса Bulk может быть пропущена, 0 - без защиты от голодания
+    pub bulk_max_skips:usize,
+    ///Предельное количество задач, новые задачи сверх него отвергаются, кроме ответов Storage для карты
+    pub capacity:usize,
+    ///Если количество задач достигает этой отметки, Balancer-у сообщается о перегрузке
+    pub high_watermark:usize,
+    ///Если количество задач опускается до этой отметки, Balancer-у сообщается о восстановлении
+    pub low_watermark:usize,
+}
+
+///Журнал задач
+pub struct JournalProperties {
+    ///Путь к файлу журнала, None(пустая строка в properties.cfg) - журнал не ведётся
+    pub path:Option<String>,
+}
+
+///Таймауты состояний Автомата, None(0 в properties.cfg) - состояние не ограничено по времени.
+///По истечении таймаута Автомат откатывается в безопасное состояние
+#[derive(Clone)]
+pub struct AutomatProperties {
+    pub map_generation_timeout:Option<Duration>,
+    pub map_loading_timeout:Option<Duration>,
+    pub map_saving_timeout:Option<Duration>,
+    pub map_closing_timeout:Option<Duration>,
+    ///Сколько ждать подтверждений Goodbye от серверов при выключении, выключение ограничено всегда
+    pub goodbye_timeout:Duration,
+}
+
+///Игровой цикл состояния Playing, без секции game_loop в properties.cfg - 20 тиков в секунду и догоняние до 5 тиков
+pub struct GameLoopProperties {
+    ///Количество тиков в секунду, от 1 до 1000
+    pub tick_rate:u32,
+    ///Сколько тиков можно выполнить подряд, догоняя отставание, остальные отбрасываются, не меньше 1
+    pub max_catch_up_ticks:usize,
+}
+
+///Пул рабочих потоков
+pub struct WorkersProperties {
+    ///Количество рабочих потоков, 0 - задачи выполняет поток Handler
+    pub count:usize,
 }
 
 define_error!(Error,
@@ -106,10 +163,188 @@ impl Properties {
         let handler_properties=HandlerProperties::read(&handler_struct)?;
         */
 
+        //Старые properties.cfg не знают об этих секциях, тогда используются значения по умолчанию
+        let tasks_queue_properties=match properties.get_struct("tasks_queue") {
+            Ok(tasks_queue_struct) => TasksQueueProperties::read(&tasks_queue_struct)?,
+            Err(_) => TasksQueueProperties::default(),
+        };
+
+        let workers_properties=match properties.get_struct("workers") {
+            Ok(workers_struct) => WorkersProperties::read(&workers_struct)?,
+            Err(_) => WorkersProperties::default(),
+        };
+
+        let journal_properties=match properties.get_struct("journal") {
+            Ok(journal_struct) => JournalProperties::read(&journal_struct)?,
+            Err(_) => JournalProperties::default(),
+        };
+
+        let automat_properties=match properties.get_struct("automat") {
+            Ok(automat_struct) => AutomatProperties::read(&automat_struct)?,
+            Err(_) => AutomatProperties::default(),
+        };
+
+        let game_loop_properties=match properties.get_struct("game_loop") {
+            Ok(game_loop_struct) => GameLoopProperties::read(&game_loop_struct)?,
+            Err(_) => GameLoopProperties::default(),
+        };
+
         let properties=Properties{
-            argument
+            argument,
+            tasks_queue:tasks_queue_properties,
+            workers:workers_properties,
+            journal:journal_properties,
+            automat:automat_properties,
+            game_loop:game_loop_properties,
         };
 
         ok!(Arc::new(properties))
     }
 }
+
+///Читает целое поле секции section, которое не может быть меньше min
+fn read_integer(section_struct:&Struct, section:&str, name:&str, min:i64) -> Result<u64,Error> {
+    let value=section_struct.get_integer(name)?.value;
+
+    if value < min {
+        return err!(Error::ConfigError, format!("{}: {} >= {} is expected", section, name, min));
+    }
+
+    ok!(value as u64)
+}
+
+impl TasksQueueProperties {
+    pub fn read(tasks_queue_struct:&Struct) -> Result<Self,Error> {
+        let integer=|name:&str, min:i64| read_integer(tasks_queue_struct, "tasks_queue", name, min);
+
+        let tasks_queue_properties=TasksQueueProperties{
+            normal_max_skips:integer("normal_max_skips", 0)? as usize,
+            bulk_max_skips:integer("bulk_max_skips", 0)? as usize,
+            capacity:integer("capacity", 1)? as usize,
+            high_watermark:integer("high_watermark", 1)? as usize,
+            low_watermark:integer("low_watermark", 0)? as usize,
+        };
+
+        if tasks_queue_properties.low_watermark >= tasks_queue_properties.high_watermark ||
+            tasks_queue_properties.high_watermark > tasks_queue_properties.capacity {
+            return err!(Error::ConfigError, "tasks_queue: low_watermark < high_watermark <= capacity is expected".to_string());
+        }
+
+        ok!(tasks_queue_properties)
+    }
+}
+
+impl Default for TasksQueueProperties {
+    fn default() -> Self {
+        TasksQueueProperties{
+            normal_max_skips:8,
+            bulk_max_skips:16,
+            capacity:100_000,
+            high_watermark:80_000,
+            low_watermark:20_000,
+        }
+    }
+}
+
+impl WorkersProperties {
+    pub fn read(workers_struct:&Struct) -> Result<Self,Error> {
+        let workers_properties=WorkersProperties{
+            count:read_integer(workers_struct, "workers", "count", 0)? as usize,
+        };
+
+        ok!(workers_properties)
+    }
+}
+
+impl Default for WorkersProperties {
+    fn default() -> Self {
+        WorkersProperties{
+            count:2,
+        }
+    }
+}
+
+impl JournalProperties {
+    pub fn read(journal_struct:&Struct) -> Result<Self,Error> {
+        let path=journal_struct.get_string("path")?.value.to_string();
+
+        let journal_properties=JournalProperties{
+            path:if path.is_empty() {None} else {Some(path)},
+        };
+
+        ok!(journal_properties)
+    }
+}
+
+impl Default for JournalProperties {
+    ///Без секции journal журнал не ведётся
+    fn default() -> Self {
+        JournalProperties{
+            path:None,
+        }
+    }
+}
+
+impl AutomatProperties {
+    pub fn read(automat_struct:&Struct) -> Result<Self,Error> {
+        let timeout=|name:&str| -> Result<Option<Duration>,Error> {
+            let seconds=read_integer(automat_struct, "automat", name, 0)?;
+
+            ok!(if seconds==0 {None} else {Some(Duration::new(seconds,0))})
+        };
+
+        let automat_properties=AutomatProperties{
+            map_generation_timeout:timeout("map_generation_timeout")?,
+            map_loading_timeout:timeout("map_loading_timeout")?,
+            map_saving_timeout:timeout("map_saving_timeout")?,
+            map_closing_timeout:timeout("map_closing_timeout")?,
+            goodbye_timeout:match automat_struct.get_integer("goodbye_timeout") {
+                Ok(_) => Duration::new(read_integer(automat_struct, "automat", "goodbye_timeout", 1)?,0),
+                Err(_) => Duration::new(DEFAULT_GOODBYE_TIMEOUT,0),//Старые properties.cfg не знают о прощании
+            },
+        };
+
+        ok!(automat_properties)
+    }
+}
+
+impl Default for AutomatProperties {
+    fn default() -> Self {
+        let timeout=Some(Duration::new(DEFAULT_STATE_TIMEOUT,0));
+
+        AutomatProperties{
+            map_generation_timeout:timeout,
+            map_loading_timeout:timeout,
+            map_saving_timeout:timeout,
+            map_closing_timeout:timeout,
+            goodbye_timeout:Duration::new(DEFAULT_GOODBYE_TIMEOUT,0),
+        }
+    }
+}
+
+impl GameLoopProperties {
+    pub fn read(game_loop_struct:&Struct) -> Result<Self,Error> {
+        let tick_rate=read_integer(game_loop_struct, "game_loop", "tick_rate", 1)?;
+        let max_catch_up_ticks=read_integer(game_loop_struct, "game_loop", "max_catch_up_ticks", 1)?;
+
+        if tick_rate > 1000 {
+            return err!(Error::ConfigError, "game_loop: 1 <= tick_rate <= 1000 is expected".to_string());
+        }
+
+        let game_loop_properties=GameLoopProperties{
+            tick_rate:tick_rate as u32,
+            max_catch_up_ticks:max_catch_up_ticks as usize,
+        };
+
+        ok!(game_loop_properties)
+    }
+}
+
+impl Default for GameLoopProperties {
+    fn default() -> Self {
+        GameLoopProperties{
+            tick_rate:20,
+            max_catch_up_ticks:5,
+        }
+    }
+}
@@ -1,3 +1,364 @@
+//!Задачи, которые Handler выполняет в промежутках между обработкой команд.
 
+use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::Instant;
+use std::hash::{Hash,Hasher};
+use std::collections::hash_map::DefaultHasher;
+
+use common_messages::HandlerToStorage;
+use task_graph::TaskGraphNode;
+use sender::FamiliarityLists;
+
+use ::ResourceID;
+
+///Количество приоритетов(полос в TasksQueue)
+pub const TASK_PRIORITIES:usize=3;
+
+///На сколько частей делится шаг генерации карты, между частями задача проверяет отмену и крайний срок
+const MAP_GENERATION_PARTS:usize=16;
+
+///Приоритет задачи, полосы TasksQueue обслуживаются в порядке возрастания значения
+#[derive(Debug,Copy,Clone,Eq,PartialEq)]
+pub enum TaskPriority{
+    ///Служебные задачи: heartbeat, знакомство, выключение
+    Control=0,
+    ///Обычные игровые задачи
+    Normal=1,
+    ///Массовые задачи: генерация карты, запись в Storage
+    Bulk=2,
+}
+
+///Ключ уведомлений о создании ресурсов
+const RESOURCE_CREATION_KEY:u64=0;
+
+///Задача
 pub enum Task{
-} 
+    ///Storage создал ресурс
+    ResourceCreated(ResourceID),
+    ///Storage прислал ресурс
+    Resource(ResourceID,Vec<u8>),
+    ///Шаг генерации карты
+    GenerateMap(String,MapGenerationStep),
+    ///Служебная работа Handler-а
+    Control(ControlTask),
+}
+
+///Служебная работа, её выполняет поток Handler, тк она обращается к Sender и Автомату.
+///Выполняется и во время паузы очереди, поэтому Balancer получает StillAlive даже в Frozen
+pub enum ControlTask{
+    ///Отправить StillAlive Balancer-у
+    Heartbeat,
+    ///Познакомиться с серверами из списков
+    Familiarize(Box<FamiliarityLists>),
+    ///Попрощаться с серверами перед выключением
+    SayGoodbye,
+}
+
+///Шаги генерации карты, выполняются по порядку графом задач
+#[derive(Debug,Copy,Clone,Eq,PartialEq)]
+pub enum MapGenerationStep{
+    Terrain,
+    Regions,
+    Objects,
+    ///Сохранение карты в Storage
+    Persistence,
+}
+
+///Ключ задачи, задачи с одинаковым ключом выполняются последовательно в порядке добавления,
+///задачи с разными ключами - параллельно
+#[derive(Clone)]
+pub enum TaskKey{
+    ///Задача работает с ресурсом
+    Resource(ResourceID),
+    ///Произвольный ключ
+    Custom(u64),
+}
+
+///Токен отмены, общий для группы задач(например, всех задач текущей карты)
+#[derive(Clone)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+///Задача вместе с токеном отмены и крайним сроком, в таком виде она хранится в TasksQueue
+pub struct TaskEntry{
+    pub task:Task,
+    ///Ключ, по умолчанию берётся из задачи
+    pub key:Option<TaskKey>,
+    ///Задача входит в граф задач
+    pub graph_node:Option<TaskGraphNode>,
+    ///Номер задачи в журнале
+    pub journal_id:Option<u64>,
+    pub cancellation_token:CancellationToken,
+    ///Крайний срок, после него задача не начинается, а длительная задача прерывается
+    pub deadline:Option<Instant>,
+}
+
+///Сведения о задаче, которые нужны TasksQueue после её выполнения
+#[derive(Debug,Copy,Clone)]
+pub struct TaskReceipt{
+    pub graph_node:Option<TaskGraphNode>,
+    pub journal_id:Option<u64>,
+}
+
+///TaskError - Ошибка выполнения задачи
+///Cancelled:Задача отменена до или во время выполнения
+///Expired:Крайний срок задачи истёк до или во время выполнения
+
+define_error!( TaskError,
+    Cancelled(task:String) =>
+        "Task {1} has been cancelled",
+    Expired(task:String) =>
+        "Deadline of task {1} has expired"
+);
+
+///Результат выполнения задачи, применяется в потоке Handler, тк только там можно обращаться к Sender и Автомату
+pub enum TaskOutput{
+    ///Больше ничего делать не нужно
+    Done,
+    ///Отправить сообщения Storage текущей карты
+    ToStorage(Vec<HandlerToStorage>),
+    ///Storage создал ресурс карты
+    ResourceCreated(ResourceID),
+    ///Storage прислал ресурс карты
+    ResourceLoaded(ResourceID,Vec<u8>),
+    ///Выполнить служебную работу
+    Control(ControlTask),
+}
+
+impl TaskKey{
+    ///Номер шарда TasksQueue, в который попадает задача с этим ключом
+    pub fn shard(&self, shards:usize) -> usize {
+        let mut hasher=DefaultHasher::new();
+
+        match *self {
+            TaskKey::Resource(ref resource_id) => {
+                0u8.hash(&mut hasher);
+                resource_id.code().hash(&mut hasher);
+            },
+            TaskKey::Custom(key) => {
+                1u8.hash(&mut hasher);
+                key.hash(&mut hasher);
+            },
+        }
+
+        (hasher.finish() % shards as u64) as usize
+    }
+}
+
+impl CancellationToken{
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    ///Возвращает TaskError::Cancelled, если токен отменён, длительные задачи вызывают её между шагами
+    pub fn check(&self, task:&Task) -> Result<(),TaskError> {
+        if self.is_cancelled() {
+            return err!(TaskError::Cancelled, task.to_string());
+        }
+
+        ok!()
+    }
+
+    pub fn is_same(&self, other:&CancellationToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl TaskEntry{
+    ///Задача без крайнего срока, которую нельзя отменить извне
+    pub fn new(task:Task) -> Self {
+        TaskEntry{
+            key:task.key(),
+            graph_node:None,
+            journal_id:None,
+            task,
+            cancellation_token:CancellationToken::new(),
+            deadline:None,
+        }
+    }
+
+    pub fn with_cancellation_token(task:Task, cancellation_token:CancellationToken) -> Self {
+        TaskEntry{
+            key:task.key(),
+            graph_node:None,
+            journal_id:None,
+            task,
+            cancellation_token,
+            deadline:None,
+        }
+    }
+
+    ///deadline - None, если задача не ограничена по времени
+    pub fn with_deadline(task:Task, cancellation_token:CancellationToken, deadline:Option<Instant>) -> Self {
+        TaskEntry{
+            key:task.key(),
+            graph_node:None,
+            journal_id:None,
+            task,
+            cancellation_token,
+            deadline,
+        }
+    }
+
+    pub fn receipt(&self) -> TaskReceipt {
+        TaskReceipt{
+            graph_node:self.graph_node,
+            journal_id:self.journal_id,
+        }
+    }
+
+    ///Выполняет задачу, если она не отменена и её крайний срок не истёк, может вызываться из любого потока
+    pub fn execute(self) -> Result<TaskOutput,TaskError> {
+        self.task.check(&self.cancellation_token, self.deadline)?;
+
+        self.task.execute(&self.cancellation_token, self.deadline)
+    }
+}
+
+impl Task{
+    ///Выполняет задачу, может вызываться из любого потока.
+    ///Длительная задача прерывается, если её отменили или её крайний срок истёк
+    pub fn execute(self, cancellation_token:&CancellationToken, deadline:Option<Instant>) -> Result<TaskOutput,TaskError> {
+        match self {
+            Task::ResourceCreated(resource_id) => {
+                info!("Created {}",resource_id);
+
+                return ok!(TaskOutput::ResourceCreated(resource_id));
+            },
+            Task::Resource(resource_id, data) => {
+                info!("Resource {} ({} bytes)",resource_id,data.len());
+
+                return ok!(TaskOutput::ResourceLoaded(resource_id, data));
+            },
+            Task::GenerateMap(ref map_name, step) => {
+                debug!("Generating {:?} of map \"{}\"",step,map_name);
+
+                for _ in 0..MAP_GENERATION_PARTS {
+                    self.check(cancellation_token, deadline)?;
+                    //TODO генерация части карты
+                }
+
+                match step {
+                    MapGenerationStep::Persistence => {
+                        let messages=vec![
+                            HandlerToStorage::CreateResource(0, vec![1,2,3]),
+                            HandlerToStorage::CreateResource(0, vec![1;1000]),
+                        ];
+
+                        return ok!(TaskOutput::ToStorage(messages));
+                    },
+                    _ => {},
+                }
+            },
+            Task::Control(control_task) => {
+                debug!("Control task {}",control_task);
+
+                return ok!(TaskOutput::Control(control_task));
+            },
+        }
+
+        ok!(TaskOutput::Done)
+    }
+
+    ///Возвращает TaskError, если задача отменена или её крайний срок истёк
+    pub fn check(&self, cancellation_token:&CancellationToken, deadline:Option<Instant>) -> Result<(),TaskError> {
+        cancellation_token.check(self)?;
+
+        match deadline {
+            Some(deadline) if deadline <= Instant::now() =>
+                return err!(TaskError::Expired, self.to_string()),
+            _ => {},
+        }
+
+        ok!()
+    }
+
+    ///Ключ для последовательного выполнения задач, работающих с одним ресурсом.
+    ///Storage отвечает ResourceCreated в порядке CreateResource и Handler сопоставляет их по этому порядку,
+    ///поэтому все уведомления о создании ресурсов выполняются одним шардом
+    pub fn key(&self) -> Option<TaskKey> {
+        match *self{
+            Task::ResourceCreated(..) => Some(TaskKey::Custom(RESOURCE_CREATION_KEY)),
+            Task::Resource(ref resource_id, _) => Some(TaskKey::Resource(resource_id.clone())),
+            Task::GenerateMap(..) => None,
+            Task::Control(..) => None,
+        }
+    }
+
+    pub fn priority(&self) -> TaskPriority {
+        match *self{
+            Task::ResourceCreated(..) => TaskPriority::Bulk,
+            Task::Resource(..) => TaskPriority::Normal,
+            Task::GenerateMap(..) => TaskPriority::Bulk,
+            Task::Control(..) => TaskPriority::Control,
+        }
+    }
+}
+
+impl std::fmt::Display for Task{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self{
+            Task::ResourceCreated(ref resource_id) => write!(f, "ResourceCreated({})", resource_id),
+            Task::Resource(ref resource_id, ref data) => write!(f, "Resource({}, {} bytes)", resource_id, data.len()),
+            Task::GenerateMap(ref map_name, step) => write!(f, "GenerateMap(\"{}\", {:?})", map_name, step),
+            Task::Control(ref control_task) => write!(f, "Control({})", control_task),
+        }
+    }
+}
+
+impl std::fmt::Display for ControlTask{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self{
+            ControlTask::Heartbeat => write!(f, "Heartbeat"),
+            ControlTask::Familiarize(..) => write!(f, "Familiarize"),
+            ControlTask::SayGoodbye => write!(f, "SayGoodbye"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration,Instant};
+
+    use super::*;
+
+    fn step(deadline:Option<Instant>) -> TaskEntry {
+        TaskEntry::with_deadline(Task::GenerateMap("map".to_string(), MapGenerationStep::Terrain), CancellationToken::new(), deadline)
+    }
+
+    #[test]
+    fn expired_task_is_not_executed() {
+        match step(Some(Instant::now())).execute() {
+            Err(TaskError::Expired(..)) => {},
+            _ => panic!("TaskError::Expired is expected"),
+        }
+    }
+
+    #[test]
+    fn task_runs_before_deadline() {
+        assert!(step(Some(Instant::now() + Duration::new(60,0))).execute().is_ok());
+        assert!(step(None).execute().is_ok());
+    }
+
+    #[test]
+    fn cancelled_task_is_not_executed() {
+        let entry=step(None);
+        entry.cancellation_token.cancel();
+
+        match entry.execute() {
+            Err(TaskError::Cancelled(..)) => {},
+            _ => panic!("TaskError::Cancelled is expected"),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//!Граф зависимых задач. Задача попадает в TasksQueue только после того, как выполнены все задачи,
+//!от которых она зависит. Если задача завершается с ошибкой, все зависящие от неё задачи отменяются.
+//!Когда граф выполнен без ошибок, Handler получает AutomatSignal, заданный on_complete, а если граф провалился -
+//!AutomatSignal, заданный on_failure. Граф, задачи которого отменены токеном(TasksQueue::cancel_map_tasks),
+//!удаляется целиком и сигналов не присылает, поэтому опоздавшая задача не затронет следующую стадию.
+
+use std;
+
+use std::time::Instant;
+use std::collections::HashMap;
+
+use automat::AutomatSignal;
+use task::{TaskEntry,CancellationToken};
+use journal::RecoveredNode;
+
+///Номер задачи в графе
+pub type TaskNode=usize;
+
+///Положение задачи в выполняющемся графе
+#[derive(Debug,Copy,Clone,Eq,PartialEq)]
+pub struct TaskGraphNode{
+    pub graph:usize,
+    pub node:TaskNode,
+}
+
+///Граф задач, собирается до отправки в TasksQueue::push_graph
+pub struct TaskGraph{
+    entries:Vec<TaskEntry>,
+    dependencies:Vec<Vec<TaskNode>>,
+    on_complete:Option<AutomatSignal>,
+    on_failure:Option<AutomatSignal>,
+    ///Номер стадии карты в журнале, задачи графа записываются в журнал вместе с ней
+    journal_stage:Option<u64>,
+}
+
+///Граф, выполняющийся в TasksQueue
+pub struct RunningGraph{
+    ///Задачи, которые ещё не отправлены в очередь
+    waiting:Vec<Option<TaskEntry>>,
+    ///Сколько зависимостей каждой задачи ещё не выполнено
+    dependencies_left:Vec<usize>,
+    dependents:Vec<Vec<TaskNode>>,
+    ///Задачи, которые ещё не выполнены и не отменены
+    unfinished:usize,
+    failed:bool,
+    on_complete:Option<AutomatSignal>,
+    on_failure:Option<AutomatSignal>,
+    ///Токены отмены задач графа
+    cancellation_tokens:Vec<CancellationToken>,
+}
+
+impl TaskGraph{
+    pub fn new() -> Self {
+        TaskGraph{
+            entries:Vec::new(),
+            dependencies:Vec::new(),
+            on_complete:None,
+            on_failure:None,
+            journal_stage:None,
+        }
+    }
+
+    ///Граф из невыполненных задач стадии, восстановленной из журнала. Зависимости от уже выполненных задач
+    ///отбрасываются, задачи сохраняют свои номера в журнале
+    pub fn recover(mut nodes:Vec<RecoveredNode>, cancellation_token:&CancellationToken, deadline:Option<Instant>) -> Self {
+        let mut graph=TaskGraph::new();
+        let mut recovered=HashMap::new();
+
+        //Задача зависит только от задач с меньшими номерами
+        nodes.sort_by_key(|recovered_node| recovered_node.node);
+
+        for recovered_node in nodes {
+            let dependencies=recovered_node.dependencies.iter()
+                .filter_map(|dependency| recovered.get(dependency).cloned())
+                .collect::<Vec<TaskNode>>();
+
+            let mut entry=TaskEntry::with_deadline(recovered_node.task, cancellation_token.clone(), deadline);
+            entry.journal_id=Some(recovered_node.id);
+
+            let node=graph.add(entry, &dependencies[..]);
+            recovered.insert(recovered_node.node, node);
+        }
+
+        graph
+    }
+
+    ///Добавляет задачу, которая выполнится после dependencies. Зависеть можно только от уже добавленных
+    ///задач, поэтому граф всегда ацикличен
+    pub fn add(&mut self, entry:TaskEntry, dependencies:&[TaskNode]) -> TaskNode {
+        let node=self.entries.len();
+
+        for &dependency in dependencies.iter() {
+            assert!(dependency < node, "Task may depend only on previously added tasks");
+        }
+
+        self.entries.push(entry);
+        self.dependencies.push(dependencies.to_vec());
+
+        node
+    }
+
+    ///Сигнал, который получит Автомат, когда весь граф будет выполнен без ошибок
+    pub fn on_complete(&mut self, signal:AutomatSignal) {
+        self.on_complete=Some(signal);
+    }
+
+    ///Сигнал, который получит Автомат, если задача графа завершится с ошибкой
+    pub fn on_failure(&mut self, signal:AutomatSignal) {
+        self.on_failure=Some(signal);
+    }
+
+    ///Задачи графа, ещё не записанные в журнал, будут записаны как задачи стадии stage_id
+    pub fn journal_in(&mut self, stage_id:Option<u64>) {
+        self.journal_stage=stage_id;
+    }
+
+    pub fn journal_stage(&self) -> Option<u64> {
+        self.journal_stage
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    ///Задача узла node и её зависимости
+    pub fn node_mut(&mut self, node:TaskNode) -> (&mut TaskEntry,&[TaskNode]) {
+        (&mut self.entries[node], &self.dependencies[node][..])
+    }
+}
+
+impl RunningGraph{
+    ///Возвращает граф и задачи, у которых нет зависимостей
+    pub fn start(graph:TaskGraph) -> (Self,Vec<(TaskNode,TaskEntry)>) {
+        let TaskGraph{entries, dependencies, on_complete, on_failure, ..}=graph;
+
+        let mut dependents=vec![Vec::new(); entries.len()];
+
+        for (node, node_dependencies) in dependencies.iter().enumerate() {
+            for &dependency in node_dependencies.iter() {
+                dependents[dependency].push(node);
+            }
+        }
+
+        let mut cancellation_tokens:Vec<CancellationToken>=Vec::new();
+
+        for entry in entries.iter() {
+            if !cancellation_tokens.iter().any(|token| token.is_same(&entry.cancellation_token)) {
+                cancellation_tokens.push(entry.cancellation_token.clone());
+            }
+        }
+
+        let mut running_graph=RunningGraph{
+            unfinished:entries.len(),
+            waiting:entries.into_iter().map(|entry| Some(entry)).collect(),
+            dependencies_left:dependencies.iter().map(|node_dependencies| node_dependencies.len()).collect(),
+            dependents,
+            failed:false,
+            on_complete,
+            on_failure,
+            cancellation_tokens,
+        };
+
+        let ready=(0..running_graph.waiting.len())
+            .filter(|&node| running_graph.dependencies_left[node]==0)
+            .collect::<Vec<TaskNode>>();
+
+        let ready_entries=running_graph.release(ready);
+
+        (running_graph,ready_entries)
+    }
+
+    ///Отмечает задачу выполненной, возвращает задачи, все зависимости которых теперь выполнены.
+    ///Если задача завершилась с ошибкой, то все зависящие от неё задачи отменяются
+    pub fn finish(&mut self, node:TaskNode, succeeded:bool) -> Vec<(TaskNode,TaskEntry)> {
+        self.unfinished-=1;
+
+        if !succeeded {
+            self.failed=true;
+            self.cancel_dependents(node);
+
+            return Vec::new();
+        }
+
+        let mut ready=Vec::new();
+
+        for &dependent in self.dependents[node].iter() {
+            self.dependencies_left[dependent]-=1;
+
+            if self.dependencies_left[dependent]==0 && self.waiting[dependent].is_some() {
+                ready.push(dependent);
+            }
+        }
+
+        self.release(ready)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.unfinished==0
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    ///Отменяет ли токен задачи графа
+    pub fn is_cancelled_by(&self, cancellation_token:&CancellationToken) -> bool {
+        self.cancellation_tokens.iter().any(|token| token.is_same(cancellation_token))
+    }
+
+    ///Сигнал завершения: on_complete, если граф выполнен без ошибок, иначе on_failure
+    pub fn take_signal(&mut self) -> Option<AutomatSignal> {
+        match self.failed {
+            false => self.on_complete.take(),
+            true => self.on_failure.take(),
+        }
+    }
+
+    fn release(&mut self, nodes:Vec<TaskNode>) -> Vec<(TaskNode,TaskEntry)> {
+        nodes.into_iter().filter_map(|node| {
+            self.waiting[node].take().map(|entry| (node,entry))
+        }).collect()
+    }
+
+    fn cancel_dependents(&mut self, node:TaskNode) {
+        let mut stack=self.dependents[node].clone();
+
+        while let Some(dependent)=stack.pop() {
+            match self.waiting[dependent].take() {
+                Some(entry) => {
+                    debug!("Task {} has been cancelled, its dependency has failed", entry.task);
+                    self.unfinished-=1;
+                    stack.extend_from_slice(&self.dependents[dependent]);
+                },
+                None => {},
+            }
+        }
+    }
+}
@@ -1,25 +1,922 @@
+//!Очередь задач, общая для потоков IpcListener и Handler.
+//!Задачи раскладываются по полосам приоритетов(TaskPriority), полосы обслуживаются по порядку,
+//!но непустая полоса, пропущенная слишком много раз подряд, обслуживается вне очереди.
+//!Рабочие потоки ждут задачи с помощью wait_pop.
+//!Задачи текущей карты(push_map_task) разделяют один токен отмены, Автомат отменяет их все, когда карта закрывается.
+//!Задачи с ключом(TaskKey) попадают в шард своего ключа, каждый шард обслуживает только один рабочий поток,
+//!поэтому задачи с одинаковым ключом выполняются последовательно и по порядку. У каждого шарда свои полосы приоритетов,
+//!рабочий поток берёт более приоритетную задачу из своего шарда или из общих полос.
+//!Очередь ограничена: при достижении high_watermark Handler получает TasksQueueOverloaded и сообщает
+//!Balancer-у о перегрузке, при опускании до low_watermark - TasksQueueRecovered. Задачи сверх capacity отвергаются,
+//!кроме задач карты: это ответы Storage на запросы самого Handler-а, без них карта никогда не загрузится, и служебных задач.
+//!Служебные задачи(TaskPriority::Control) выдаются и во время паузы, они не записываются в журнал.
+//!Графы задач(TaskGraph) хранятся здесь же, задача графа попадает в полосы, когда выполнены её зависимости.
+//!Если журнал включён, принятые задачи записываются в него, а после выполнения(finish) отмечаются выполненными.
+//!Задачи графа стадии карты(TaskGraph::journal_in) записываются вместе с зависимостями, чтобы граф можно было достроить после перезапуска.
+//!Журнал защищён собственным Mutex-ом и пишется вне Mutex-а очереди, чтобы запись на диск не задерживала рабочие потоки.
+
 use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+
+use std::sync::{Arc,Mutex,Condvar};
+use std::collections::{VecDeque,HashMap};
 
+use task::{TASK_PRIORITIES,TaskPriority};
+use task::{TaskEntry,TaskReceipt,CancellationToken};
+use handler::{HandlerSender,HandlerCommand};
+use automat::AutomatSignal;
+use task_graph::{TaskGraph,RunningGraph,TaskGraphNode,TaskNode};
+use journal::{Journal,MapStage};
 
-use std::sync::{Arc,Mutex,RwLock};
+use ::Task;
+use ::TasksQueueProperties;
 
 pub type ArcTasksQueue=Arc<TasksQueue>;
 
+///Очередь задач
 pub struct TasksQueue {
+    inner:Mutex<InnerTasksQueue>,
+    ///Сигнализирует ждущим рабочим потокам о появлении задачи или закрытии очереди
+    ready:Condvar,
+    journal:Option<Mutex<Journal>>,
+}
+
+///Внутренняя очередь задач
+struct InnerTasksQueue {
+    ///Задачи без ключа, их может взять любой рабочий поток
+    shared:Lanes,
+    ///Задачи с ключом, шард i обслуживает только рабочий поток i, порядок задач одной полосы сохраняется
+    shards:Vec<Lanes>,
+    ///Токен отмены задач текущей карты
+    map_token:CancellationToken,
+    ///Очередь закрыта, рабочие потоки должны завершиться
+    closed:bool,
+    ///Выполнение задач приостановлено, задачи продолжают приниматься
+    paused:bool,
+    ///Выполнение задач остановлено на время снимка карты, независимо от паузы Автомата
+    quiesced:bool,
+    ///Задачи, которые взяты из очереди и ещё не завершены
+    running:usize,
+
+    capacity:usize,
+    high_watermark:usize,
+    low_watermark:usize,
+    ///Отметка high_watermark достигнута и ещё не пройдена low_watermark
+    overloaded:bool,
+    handler_sender:HandlerSender,
+
+    graphs:HashMap<usize,RunningGraph>,
+    next_graph:usize,
+}
+
+///Полосы приоритетов
+struct Lanes {
+    lanes:[VecDeque<TaskEntry>;TASK_PRIORITIES],
+    ///Сколько раз подряд непустая полоса была пропущена
+    skipped:[usize;TASK_PRIORITIES],
+    ///Предел пропусков для каждой полосы, 0 - без защиты от голодания
+    max_skips:[usize;TASK_PRIORITIES],
+}
+
+///TransactionError - Ошибка транзакции
+///Poisoned:Mutex сломан(FatalError)
+
+define_error!( TransactionError,
+    Poisoned() =>
+        "Poisoned"
+);
+
+#[macro_export]
+macro_rules! do_tasks_queue_transaction {
+    [$operation:expr] => {
+        match $operation {
+            Ok(rv) => rv,
+            Err(tasks_queue::TransactionError::Poisoned(error_info)) => return Err(Error::Poisoned(error_info)),
+        }
+    };
 }
 
 impl TasksQueue {
-    pub fn new() -> Self {
+    ///shards - количество рабочих потоков, если их нет, то все шарды обслуживает поток Handler
+    ///journal - журнал задач, если он включён
+    pub fn new(properties:&TasksQueueProperties, shards:usize, handler_sender:HandlerSender, journal:Option<Journal>) -> Self {
+        let max_skips=[0, properties.normal_max_skips, properties.bulk_max_skips];
+
+        let inner=InnerTasksQueue{
+            shared:Lanes::new(max_skips),
+            shards:(0..std::cmp::max(shards,1)).map(|_| Lanes::new(max_skips)).collect(),
+            map_token:CancellationToken::new(),
+            closed:false,
+            paused:false,
+            quiesced:false,
+            running:0,
+
+            capacity:properties.capacity,
+            high_watermark:properties.high_watermark,
+            low_watermark:properties.low_watermark,
+            overloaded:false,
+            handler_sender,
+
+            graphs:HashMap::new(),
+            next_graph:0,
+        };
+
         TasksQueue {
+            inner:Mutex::new(inner),
+            ready:Condvar::new(),
+            journal:journal.map(|journal| Mutex::new(journal)),
+        }
+    }
+
+    pub fn new_arc(properties:&TasksQueueProperties, shards:usize, handler_sender:HandlerSender, journal:Option<Journal>) -> ArcTasksQueue {
+        Arc::new( Self::new(properties, shards, handler_sender, journal) )
+    }
+
+    ///Добавляет задачу, не относящуюся к карте, в конец полосы её приоритета или шарда её ключа.
+    ///Возвращает false, если очередь заполнена и задача отброшена
+    pub fn push(&self, task:Task) -> Result<bool,TransactionError> {
+        self.push_entry(TaskEntry::new(task))
+    }
+
+    ///Добавляет задачу текущей карты, она будет отменена при закрытии карты.
+    ///Задача принимается, даже если очередь заполнена
+    pub fn push_map_task(&self, task:Task) -> Result<(),TransactionError> {
+        let journal_id=self.journal_accept(&task)?;
+
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        let mut entry=TaskEntry::with_cancellation_token(task, queue.map_token.clone());
+        entry.journal_id=journal_id;
+        self.push_locked(&mut queue, entry, true);
+
+        ok!()
+    }
+
+    ///Добавляет задачу с собственным токеном отмены.
+    ///Возвращает false, если очередь заполнена и задача отброшена
+    pub fn push_entry(&self, mut entry:TaskEntry) -> Result<bool,TransactionError> {
+        if entry.graph_node.is_none() && entry.task.priority()!=TaskPriority::Control {
+            entry.journal_id=self.journal_accept(&entry.task)?;
+        }
+
+        let journal_id=entry.journal_id;
+
+        let accepted={
+            mutex_lock!(&self.inner => queue,TransactionError);
+
+            self.push_locked(&mut queue, entry, false)
+        };
+
+        if !accepted {
+            self.journal_complete(journal_id)?;
+        }
+
+        ok!(accepted)
+    }
+
+    ///Возвращает в очередь незавершённую задачу из журнала, Journal::open уже записал её под номером journal_id.
+    ///Это ответ Storage для карты, поэтому задача отменяется вместе с задачами карты.
+    ///Задача принимается, даже если очередь заполнена, иначе она была бы потеряна
+    pub fn replay(&self, journal_id:u64, task:Task) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        let mut entry=TaskEntry::with_cancellation_token(task, queue.map_token.clone());
+        entry.journal_id=Some(journal_id);
+
+        self.push_locked(&mut queue, entry, true);
+
+        ok!()
+    }
+
+    ///force - принять задачу сверх capacity, служебные задачи принимаются всегда
+    fn push_locked(&self, queue:&mut InnerTasksQueue, entry:TaskEntry, force:bool) -> bool {
+        if queue.len() >= queue.capacity {
+            if !force && entry.task.priority()!=TaskPriority::Control {
+                warn!("TasksQueue is full, task {} has been rejected", entry.task);
+                return false;
+            }
+
+            debug!("TasksQueue is full, task {} is accepted over capacity", entry.task);
+        }
+
+        let is_sharded=queue.push(entry);
+        queue.check_watermarks();
+        self.notify(is_sharded);
+
+        true
+    }
+
+    ///Задачу шарда может взять только один поток, поэтому будим всех
+    fn notify(&self, is_sharded:bool) {
+        if is_sharded {
+            self.ready.notify_all();
+        }else{
+            self.ready.notify_one();
+        }
+    }
+
+    ///Отправляет граф задач в очередь, задачи без зависимостей сразу становятся доступны.
+    ///Задачи графа не ограничиваются capacity, иначе зависящие от отброшенной задачи никогда бы не выполнились.
+    ///Если граф пуст, то сразу возвращает его сигнал завершения
+    pub fn push_graph(&self, mut graph:TaskGraph) -> Result<Option<AutomatSignal>,TransactionError> {
+        match graph.journal_stage() {
+            Some(stage_id) => {
+                for node in 0..graph.len() {
+                    let (entry, dependencies)=graph.node_mut(node);
+
+                    //Задачи восстановленного графа уже записаны
+                    if entry.journal_id.is_none() {
+                        entry.journal_id=self.journal_graph_node(stage_id, node, dependencies, &entry.task)?;
+                    }
+                }
+            },
+            None => {},
+        }
+
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        let (mut running_graph, ready)=RunningGraph::start(graph);
+
+        if running_graph.is_finished() {
+            return ok!(running_graph.take_signal());
+        }
+
+        let graph_id=queue.next_graph;
+        queue.next_graph+=1;
+
+        queue.graphs.insert(graph_id, running_graph);
+        queue.release_graph_entries(graph_id, ready);
+        self.ready.notify_all();
+
+        ok!(None)
+    }
+
+    ///Отмечает задачу выполненной, возвращает сигнал завершения графа, если задача была последней в графе,
+    ///и весь граф выполнен без ошибок
+    pub fn finish(&self, receipt:TaskReceipt, succeeded:bool) -> Result<Option<AutomatSignal>,TransactionError> {
+        let signal={
+            mutex_lock!(&self.inner => queue,TransactionError);
+
+            queue.running=queue.running.saturating_sub(1);
+            let signal=queue.finish(receipt, succeeded);
+            self.ready.notify_all();
+
+            signal
+        };
+
+        self.journal_complete(receipt.journal_id)?;
+
+        ok!(signal)
+    }
+
+    ///Сбрасывает журнал на диск, вызывается перед аварийным завершением
+    pub fn sync_journal(&self) -> Result<(),TransactionError> {
+        match self.journal {
+            Some(ref journal) => {
+                mutex_lock!(journal => journal,TransactionError);
+
+                match journal.sync() {
+                    Ok(_) => {},
+                    Err(error) => error!("Journal error: {}", error),
+                }
+            },
+            None => {},
+        }
+
+        ok!()
+    }
+
+    ///Записывает принятую задачу в журнал. Ошибка журнала не должна останавливать Handler,
+    ///задача просто не попадёт в журнал
+    fn journal_accept(&self, task:&Task) -> Result<Option<u64>,TransactionError> {
+        let journal=match self.journal {
+            Some(ref journal) => journal,
+            None => return ok!(None),
+        };
+
+        mutex_lock!(journal => journal,TransactionError);
+
+        match journal.accept(task) {
+            Ok(journal_id) => ok!(Some(journal_id)),
+            Err(error) => {
+                error!("Journal error: {}", error);
+                ok!(None)
+            }
+        }
+    }
+
+    ///Записывает задачу графа стадии stage_id вместе с её зависимостями
+    fn journal_graph_node(&self, stage_id:u64, node:TaskNode, dependencies:&[TaskNode], task:&Task) -> Result<Option<u64>,TransactionError> {
+        let journal=match self.journal {
+            Some(ref journal) => journal,
+            None => return ok!(None),
+        };
+
+        mutex_lock!(journal => journal,TransactionError);
+
+        match journal.accept_graph_node(stage_id, node, dependencies, task) {
+            Ok(journal_id) => ok!(Some(journal_id)),
+            Err(error) => {
+                error!("Journal error: {}", error);
+                ok!(None)
+            }
+        }
+    }
+
+    ///Записывает в журнал начатую стадию карты, возвращает её номер или None, если журнал не ведётся
+    pub fn journal_stage(&self, stage:&MapStage) -> Result<Option<u64>,TransactionError> {
+        let journal=match self.journal {
+            Some(ref journal) => journal,
+            None => return ok!(None),
+        };
+
+        mutex_lock!(journal => journal,TransactionError);
+
+        match journal.accept_stage(stage) {
+            Ok(journal_id) => ok!(Some(journal_id)),
+            Err(error) => {
+                error!("Journal error: {}", error);
+                ok!(None)
+            }
+        }
+    }
+
+    ///Отмечает в журнале задачу выполненной или стадию карты завершённой
+    pub fn journal_complete(&self, journal_id:Option<u64>) -> Result<(),TransactionError> {
+        let (journal, journal_id)=match (self.journal.as_ref(), journal_id) {
+            (Some(journal), Some(journal_id)) => (journal, journal_id),
+            _ => return ok!(),
+        };
+
+        mutex_lock!(journal => journal,TransactionError);
+
+        match journal.complete(journal_id) {
+            Ok(_) => {},
+            Err(error) => error!("Journal error: {}", error),
+        }
+
+        ok!()
+    }
+
+    ///Токен отмены задач текущей карты
+    pub fn map_token(&self) -> Result<CancellationToken,TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        ok!(queue.map_token.clone())
+    }
+
+    ///Отменяет все задачи текущей карты: выполняющиеся задачи увидят отменённый токен,
+    ///ждущие в очереди удаляются. Возвращает количество удалённых задач.
+    pub fn cancel_map_tasks(&self) -> Result<usize,TransactionError> {
+        let removed={
+            mutex_lock!(&self.inner => queue,TransactionError);
+
+            let map_token=std::mem::replace(&mut queue.map_token, CancellationToken::new());
+            map_token.cancel();
+
+            //Графы карты удаляются целиком: сигналы провала не нужны, задачи карты отменяет сам Автомат,
+            //он уже покинул состояние, ждавшее графы. Выполняющиеся задачи графов завершатся без сигналов
+            let graphs=queue.graphs.len();
+            queue.graphs.retain(|_, running_graph| !running_graph.is_cancelled_by(&map_token));
+
+            if graphs > queue.graphs.len() {
+                debug!("{} task graphs of map have been cancelled", graphs - queue.graphs.len());
+            }
+
+            let removed=queue.remove_where(|entry| entry.cancellation_token.is_same(&map_token));
+
+            //Задачи, удалённые из очереди, считаются завершившимися с ошибкой
+            for entry in removed.iter() {
+                queue.finish(entry.receipt(), false);
+            }
+
+            queue.check_watermarks();
+            self.ready.notify_all();
+
+            removed
+        };
+
+        for entry in removed.iter() {
+            self.journal_complete(entry.journal_id)?;
+        }
+
+        ok!(removed.len())
+    }
+
+    ///Извлекает следующую задачу для потока, обслуживающего шард shard, с учётом приоритетов
+    pub fn pop(&self, shard:usize) -> Result<Option<TaskEntry>,TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        let entry=queue.pop(shard);
+        queue.check_watermarks();
+
+        ok!(entry)
+    }
+
+    ///Ждёт задачу для потока, обслуживающего шард shard, возвращает None, если очередь закрыта
+    pub fn wait_pop(&self, shard:usize) -> Result<Option<TaskEntry>,TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        loop {
+            if queue.closed {
+                return ok!(None);
+            }
+
+            match queue.pop(shard) {
+                Some(entry) => {
+                    queue.check_watermarks();
+
+                    return ok!(Some(entry));
+                },
+                None => {}
+            }
+
+            queue=match self.ready.wait(queue) {
+                Ok(queue) => queue,
+                Err(_) => return err!(TransactionError::Poisoned),
+            };
+        }
+    }
+
+    ///Закрывает очередь и будит все рабочие потоки
+    pub fn close(&self) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        queue.closed=true;
+        self.ready.notify_all();
+
+        ok!()
+    }
+
+    ///Приостанавливает выполнение задач, уже выполняющиеся задачи завершаются
+    pub fn pause(&self) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        queue.paused=true;
 
+        ok!()
+    }
+
+    ///Возобновляет выполнение задач
+    pub fn resume(&self) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        queue.paused=false;
+        self.ready.notify_all();
+
+        ok!()
+    }
+
+    ///Останавливает выдачу задач и ждёт, пока уже взятые задачи не будут завершены(finish).
+    ///Рабочий поток отправляет результат задачи до finish, поэтому все результаты уже в канале Handler-а
+    pub fn quiesce(&self) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        queue.quiesced=true;
+
+        while queue.running > 0 {
+            queue=match self.ready.wait(queue) {
+                Ok(queue) => queue,
+                Err(_) => return err!(TransactionError::Poisoned),
+            };
         }
+
+        ok!()
     }
 
-    pub fn new_arc() -> ArcTasksQueue {
-        Arc::new( Self::new() )
+    ///Возобновляет выдачу задач после quiesce, пауза Автомата сохраняется
+    pub fn release(&self) -> Result<(),TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        queue.quiesced=false;
+        self.ready.notify_all();
+
+        ok!()
+    }
+
+    ///Есть ли задача, которую можно выполнить сейчас
+    pub fn is_task(&self) -> Result<bool,TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        ok!(queue.has_task())
     }
 
-    pub fn is_task(&self) -> bool {
-        false
+    pub fn len(&self) -> Result<usize,TransactionError> {
+        mutex_lock!(&self.inner => queue,TransactionError);
+
+        ok!(queue.len())
+    }
+}
+
+impl InnerTasksQueue {
+    fn len(&self) -> usize {
+        self.shared.len() + self.shards.iter().map(|shard| shard.len()).sum::<usize>()
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.paused || self.quiesced
+    }
+
+    fn has_task(&self) -> bool {
+        match self.is_stopped() {
+            true => !self.shared.lanes[TaskPriority::Control as usize].is_empty(),
+            false => self.len() > 0,
+        }
+    }
+
+    ///Возвращает true, если задача попала в шард
+    fn push(&mut self, entry:TaskEntry) -> bool {
+        match entry.key {
+            Some(ref key) => {
+                let shard=key.shard(self.shards.len());
+                self.shards[shard].push_back(entry);
+
+                true
+            },
+            None => {
+                self.shared.push(entry);
+
+                false
+            }
+        }
+    }
+
+    ///Извлекает задачу и считает её выполняющейся до finish
+    fn pop(&mut self, shard:usize) -> Option<TaskEntry> {
+        let entry=self.pop_lane(shard);
+
+        if entry.is_some() {
+            self.running+=1;
+        }
+
+        entry
+    }
+
+    ///Задача шарда берётся, если её полоса не менее приоритетна, чем полоса, выбранная в общих полосах.
+    ///Полосы, которые не обслужены, считаются пропущенными, в том числе полосы другой стороны
+    fn pop_lane(&mut self, shard:usize) -> Option<TaskEntry> {
+        //Остановленная очередь выдаёт только служебные задачи, у них нет ключа, поэтому они в общих полосах
+        if self.is_stopped() {
+            return self.shared.lanes[TaskPriority::Control as usize].pop_front();
+        }
+
+        let shard=shard % self.shards.len();
+
+        match (self.shards[shard].choose(), self.shared.choose()) {
+            (Some(shard_lane), Some(shared_lane)) => {
+                //Голодающая полоса одной стороны обслуживается раньше более приоритетной полосы другой
+                let take_shard=match (self.shards[shard].is_starving(shard_lane), self.shared.is_starving(shared_lane)) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    _ => shard_lane <= shared_lane,
+                };
+
+                if take_shard {
+                    self.shared.skip();
+                    self.shards[shard].take(shard_lane)
+                }else{
+                    self.shards[shard].skip();
+                    self.shared.take(shared_lane)
+                }
+            },
+            (Some(shard_lane), None) => self.shards[shard].take(shard_lane),
+            (None, Some(shared_lane)) => self.shared.take(shared_lane),
+            (None, None) => None,
+        }
+    }
+
+    ///Сообщает Handler-у о пересечении отметок. Ошибка канала означает, что Handler завершился,
+    ///тогда отметка не считается пройденной
+    fn check_watermarks(&mut self) {
+        let len=self.len();
+
+        if !self.overloaded && len >= self.high_watermark {
+            match self.handler_sender.send(HandlerCommand::TasksQueueOverloaded(len)) {
+                Ok(_) => self.overloaded=true,
+                Err(_) => warn!("Handler has finished, TasksQueue overload ({} tasks) is not reported", len),
+            }
+        }else if self.overloaded && len <= self.low_watermark {
+            match self.handler_sender.send(HandlerCommand::TasksQueueRecovered(len)) {
+                Ok(_) => self.overloaded=false,
+                Err(_) => warn!("Handler has finished, TasksQueue recovery ({} tasks) is not reported", len),
+            }
+        }
+    }
+
+    ///Удаляет из очереди задачи, для которых f возвращает true
+    fn remove_where<F>(&mut self, f:F) -> Vec<TaskEntry> where F:Fn(&TaskEntry) -> bool {
+        let mut removed=Vec::new();
+
+        let shard_lanes=self.shards.iter_mut().flat_map(|shard| shard.lanes.iter_mut());
+
+        for lane in self.shared.lanes.iter_mut().chain(shard_lanes) {
+            let entries=lane.drain(..).collect::<Vec<TaskEntry>>();
+
+            for entry in entries {
+                if f(&entry) {
+                    removed.push(entry);
+                }else{
+                    lane.push_back(entry);
+                }
+            }
+        }
+
+        removed
+    }
+
+    fn release_graph_entries(&mut self, graph:usize, entries:Vec<(TaskNode,TaskEntry)>) {
+        for (node, mut entry) in entries {
+            entry.graph_node=Some(TaskGraphNode{graph,node});
+            self.push(entry);
+        }
+
+        self.check_watermarks();
+    }
+
+    fn finish(&mut self, receipt:TaskReceipt, succeeded:bool) -> Option<AutomatSignal> {
+        match receipt.graph_node {
+            Some(graph_node) => self.finish_graph_node(graph_node, succeeded),
+            None => None,
+        }
+    }
+
+    fn finish_graph_node(&mut self, graph_node:TaskGraphNode, succeeded:bool) -> Option<AutomatSignal> {
+        let (ready, is_finished)=match self.graphs.get_mut(&graph_node.graph) {
+            Some(running_graph) => {
+                let ready=running_graph.finish(graph_node.node, succeeded);
+
+                (ready, running_graph.is_finished())
+            },
+            None => return None,
+        };
+
+        self.release_graph_entries(graph_node.graph, ready);
+
+        if !is_finished {
+            return None;
+        }
+
+        let mut running_graph=self.graphs.remove(&graph_node.graph).unwrap();
+
+        if running_graph.is_failed() {
+            warn!("Task graph #{} has failed", graph_node.graph);
+        }
+
+        running_graph.take_signal()
+    }
+}
+
+impl Lanes {
+    fn new(max_skips:[usize;TASK_PRIORITIES]) -> Self {
+        Lanes{
+            lanes:[VecDeque::with_capacity(16), VecDeque::with_capacity(64), VecDeque::with_capacity(64)],
+            skipped:[0;TASK_PRIORITIES],
+            max_skips,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.len()).sum()
+    }
+
+    fn push(&mut self, entry:TaskEntry) {
+        let priority=entry.task.priority();
+        self.lanes[priority as usize].push_back(entry);
+    }
+
+    ///Полоса, которая будет обслужена следующей: первая непустая, но голодающая полоса обслуживается вне очереди
+    fn choose(&self) -> Option<usize> {
+        let first=match (0..TASK_PRIORITIES).find(|&lane| !self.lanes[lane].is_empty()) {
+            Some(lane) => lane,
+            None => return None,
+        };
+
+        match (first+1..TASK_PRIORITIES).find(|&lane| self.is_starving(lane)) {
+            Some(lane) => Some(lane),
+            None => Some(first),
+        }
+    }
+
+    ///Извлекает задачу из полосы chosen, остальные непустые полосы считаются пропущенными
+    fn take(&mut self, chosen:usize) -> Option<TaskEntry> {
+        for lane in 0..TASK_PRIORITIES {
+            if lane==chosen {
+                self.skipped[lane]=0;
+            }else if !self.lanes[lane].is_empty() {
+                self.skipped[lane]+=1;
+            }
+        }
+
+        self.lanes[chosen].pop_front()
+    }
+
+    ///Все непустые полосы пропущены, потому что задача взята из других полос
+    fn skip(&mut self) {
+        for lane in 0..TASK_PRIORITIES {
+            if !self.lanes[lane].is_empty() {
+                self.skipped[lane]+=1;
+            }
+        }
+    }
+
+    fn is_starving(&self, lane:usize) -> bool {
+        !self.lanes[lane].is_empty() && self.max_skips[lane] > 0 && self.skipped[lane] >= self.max_skips[lane]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use task::{Task,TaskEntry,TaskKey,MapGenerationStep,ControlTask};
+    use handler::HandlerCommand;
+    use automat::AutomatSignal;
+    use task_graph::TaskGraph;
+
+    use ::ResourceID;
+    use ::TasksQueueProperties;
+
+    use super::*;
+
+    fn properties(bulk_max_skips:usize) -> TasksQueueProperties {
+        TasksQueueProperties{
+            normal_max_skips:0,
+            bulk_max_skips,
+            capacity:1000,
+            high_watermark:1000,
+            low_watermark:0,
+        }
+    }
+
+    ///Канал Handler-а возвращается, чтобы он оставался открытым, пока живёт очередь
+    fn tasks_queue(shards:usize, bulk_max_skips:usize) -> (TasksQueue,mpsc::Receiver<HandlerCommand>) {
+        let (handler_sender, handler_receiver) = mpsc::channel();
+
+        (TasksQueue::new(&properties(bulk_max_skips), shards, handler_sender, None), handler_receiver)
+    }
+
+    ///Normal задача без ключа, попадает в общие полосы
+    fn normal(code:u64) -> TaskEntry {
+        let mut entry=TaskEntry::new(Task::Resource(ResourceID::from(code), Vec::new()));
+        entry.key=None;
+        entry
+    }
+
+    fn bulk(name:&str) -> TaskEntry {
+        TaskEntry::new(Task::GenerateMap(name.to_string(), MapGenerationStep::Terrain))
+    }
+
+    fn keyed(code:u64, data:u8) -> TaskEntry {
+        TaskEntry::new(Task::Resource(ResourceID::from(code), vec![data]))
+    }
+
+    fn control() -> TaskEntry {
+        TaskEntry::new(Task::Control(ControlTask::Heartbeat))
+    }
+
+    fn pop_all(tasks_queue:&TasksQueue, shard:usize) -> Vec<String> {
+        let mut popped=Vec::new();
+
+        while let Some(entry)=tasks_queue.pop(shard).unwrap() {
+            popped.push(match entry.task {
+                Task::Resource(_, ref data) if !data.is_empty() => format!("R{}", data[0]),
+                Task::Resource(..) => "N".to_string(),
+                Task::GenerateMap(..) => "B".to_string(),
+                Task::ResourceCreated(ref resource_id) => format!("C{}", resource_id.code()),
+                Task::Control(..) => "H".to_string(),
+            });
+        }
+
+        popped
+    }
+
+    #[test]
+    fn higher_priority_lane_goes_first() {
+        let (tasks_queue, _handler_receiver) = tasks_queue(1, 0);
+
+        tasks_queue.push_entry(bulk("a")).unwrap();
+        tasks_queue.push_entry(normal(1)).unwrap();
+        tasks_queue.push_entry(bulk("b")).unwrap();
+        tasks_queue.push_entry(normal(2)).unwrap();
+
+        assert_eq!(pop_all(&tasks_queue, 0), vec!["N","N","B","B"]);
+    }
+
+    #[test]
+    fn starving_lane_is_served_out_of_turn() {
+        let (tasks_queue, _handler_receiver) = tasks_queue(1, 2);
+
+        for code in 0..5 {
+            tasks_queue.push_entry(normal(code)).unwrap();
+        }
+
+        tasks_queue.push_entry(bulk("a")).unwrap();
+        tasks_queue.push_entry(bulk("b")).unwrap();
+
+        assert_eq!(pop_all(&tasks_queue, 0), vec!["N","N","B","N","N","B","N"]);
+    }
+
+    #[test]
+    fn shard_keeps_order_of_key() {
+        let shards=4;
+        let (tasks_queue, _handler_receiver) = tasks_queue(shards, 0);
+
+        for data in 0..5 {
+            tasks_queue.push_entry(keyed(7, data)).unwrap();
+        }
+
+        let shard=TaskKey::Resource(ResourceID::from(7)).shard(shards);
+
+        for other in (0..shards).filter(|&other| other!=shard) {
+            assert!(tasks_queue.pop(other).unwrap().is_none());
+        }
+
+        assert_eq!(pop_all(&tasks_queue, shard), vec!["R0","R1","R2","R3","R4"]);
+    }
+
+    #[test]
+    fn created_resources_keep_order() {
+        let shards=4;
+        let (tasks_queue, _handler_receiver) = tasks_queue(shards, 0);
+
+        for code in 0..5 {
+            tasks_queue.push_entry(TaskEntry::new(Task::ResourceCreated(ResourceID::from(code)))).unwrap();
+        }
+
+        let shard=Task::ResourceCreated(ResourceID::from(0)).key().unwrap().shard(shards);
+
+        for other in (0..shards).filter(|&other| other!=shard) {
+            assert!(tasks_queue.pop(other).unwrap().is_none());
+        }
+
+        assert_eq!(pop_all(&tasks_queue, shard), vec!["C0","C1","C2","C3","C4"]);
+    }
+
+    #[test]
+    fn shard_competes_with_shared_lanes() {
+        let (tasks_queue, _handler_receiver) = tasks_queue(1, 2);
+
+        tasks_queue.push_entry(bulk("a")).unwrap();
+
+        for data in 0..4 {
+            tasks_queue.push_entry(keyed(1, data)).unwrap();
+        }
+
+        //Общая Bulk полоса пропускается, пока в шарде есть Normal задачи, но не дольше bulk_max_skips
+        assert_eq!(pop_all(&tasks_queue, 0), vec!["R0","R1","B","R2","R3"]);
+    }
+
+    #[test]
+    fn control_lane_is_served_while_paused() {
+        let (tasks_queue, _handler_receiver) = tasks_queue(2, 0);
+
+        tasks_queue.push_entry(normal(1)).unwrap();
+        tasks_queue.push_entry(keyed(2, 0)).unwrap();
+        tasks_queue.pause().unwrap();
+        tasks_queue.push_entry(control()).unwrap();
+
+        assert!(tasks_queue.is_task().unwrap());
+        assert_eq!(pop_all(&tasks_queue, 0), vec!["H"]);
+        assert!(!tasks_queue.is_task().unwrap());
+
+        tasks_queue.resume().unwrap();
+        assert_eq!(tasks_queue.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn control_task_is_accepted_over_capacity() {
+        let (handler_sender, _handler_receiver) = mpsc::channel();
+        let mut properties=properties(0);
+        properties.capacity=1;
+
+        let tasks_queue=TasksQueue::new(&properties, 1, handler_sender, None);
+
+        assert!(tasks_queue.push_entry(normal(1)).unwrap());
+        assert!(!tasks_queue.push_entry(normal(2)).unwrap());
+        assert!(tasks_queue.push_entry(control()).unwrap());
+
+        assert_eq!(pop_all(&tasks_queue, 0), vec!["H","N"]);
+    }
+
+    #[test]
+    fn cancelled_graph_sends_no_signal() {
+        let (tasks_queue, _handler_receiver) = tasks_queue(1, 0);
+
+        let map_token=tasks_queue.map_token().unwrap();
+        let step=|map_step| TaskEntry::with_cancellation_token(Task::GenerateMap("a".to_string(), map_step), map_token.clone());
+
+        let mut graph=TaskGraph::new();
+        let terrain=graph.add(step(MapGenerationStep::Terrain), &[]);
+        graph.add(step(MapGenerationStep::Regions), &[terrain]);
+        graph.on_complete(AutomatSignal::MapLoadedFromStorage);
+        graph.on_failure(AutomatSignal::MapLoadingFailed);
+        assert!(tasks_queue.push_graph(graph).unwrap().is_none());
+
+        let running=tasks_queue.pop(0).unwrap().unwrap();
+        assert_eq!(tasks_queue.cancel_map_tasks().unwrap(), 0);
+
+        //Задача, выполнявшаяся во время отмены, завершается без сигнала, и зависящие от неё задачи не выпускаются
+        assert!(tasks_queue.finish(running.receipt(), true).unwrap().is_none());
+        assert_eq!(tasks_queue.len().unwrap(), 0);
     }
 }
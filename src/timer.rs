@@ -0,0 +1,227 @@
+//!Служба таймеров. Событие можно отложить до заданного момента, на заданное время или повторять
+//!с заданным интервалом, а затем отменить по TimerHandle.
+//!Сработавшее событие отправляется потоку Handler командой HandlerCommand::Timer.
+
+use std;
+use nes::{ErrorInfo,ErrorInfoTrait};
+
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread::JoinHandle;
+use std::time::{Instant,Duration};
+use std::collections::BTreeMap;
+
+use handler::{HandlerSender,HandlerCommand};
+
+pub type ArcTimer=Arc<Timer>;
+
+///Событие таймера
+#[derive(Debug,Clone,Eq,PartialEq)]
+pub enum TimerEvent {
+    ///Раз в секунду Handler сообщает Balancer-у, что он жив
+    EachSecond,
+    ///Автомат слишком долго находится в одном состоянии
+    StateTimeout,
+    ///Шаг игрового цикла
+    Tick,
+}
+
+///Идентификатор запланированного события, нужен для его отмены
+#[derive(Debug,Copy,Clone,Eq,PartialEq,Ord,PartialOrd,Hash)]
+pub struct TimerHandle(usize);
+
+impl TimerHandle {
+    ///Идентификатор события, которое Timer не планировал
+    #[cfg(test)]
+    pub fn new(handle:usize) -> Self {
+        TimerHandle(handle)
+    }
+}
+
+enum TimerCommand {
+    Schedule(TimerHandle, Instant, Option<Duration>, TimerEvent),
+    Cancel(TimerHandle),
+    Shutdown,
+}
+
+///Служба таймеров, владеет потоком Handler.Timer
+pub struct Timer {
+    timer_sender:Mutex<mpsc::Sender<TimerCommand>>,
+    next_handle:AtomicUsize,
+    join_handle:Mutex<Option<JoinHandle<()>>>,
+}
+
+///TransactionError - Ошибка транзакции
+///Poisoned:Mutex сломан(FatalError)
+///BrockenChannel:Поток таймера завершился(FatalError)
+
+define_error!( TransactionError,
+    Poisoned() =>
+        "Poisoned",
+    BrockenChannel() =>
+        "Channel for Timer is broken"
+);
+
+#[macro_export]
+macro_rules! do_timer_transaction {
+    [$operation:expr] => {
+        match $operation {
+            Ok(rv) => rv,
+            Err(timer::TransactionError::Poisoned(error_info)) => return Err(Error::Poisoned(error_info)),
+            Err(timer::TransactionError::BrockenChannel(error_info)) => return Err(Error::BrockenChannel(error_info)),
+        }
+    };
+}
+
+impl Timer {
+    ///Создаёт Timer и запускает его поток
+    pub fn new(handler_sender:HandlerSender) -> Self {
+        let (timer_sender, timer_receiver) = mpsc::channel();
+
+        let join_handle=std::thread::Builder::new().name("Handler.Timer".to_string()).spawn(move|| {
+            Timer::lifecycle(timer_receiver, handler_sender);
+        }).unwrap();
+
+        Timer {
+            timer_sender:Mutex::new(timer_sender),
+            next_handle:AtomicUsize::new(0),
+            join_handle:Mutex::new(Some(join_handle)),
+        }
+    }
+
+    ///Создаёт ArcTimer или Arc<Timer>
+    pub fn new_arc(handler_sender:HandlerSender) -> ArcTimer {
+        Arc::new( Timer::new(handler_sender) )
+    }
+
+    ///Событие сработает в момент at
+    pub fn schedule_at(&self, at:Instant, event:TimerEvent) -> Result<TimerHandle,TransactionError> {
+        self.schedule(at, None, event)
+    }
+
+    ///Событие сработает через delay
+    pub fn schedule_after(&self, delay:Duration, event:TimerEvent) -> Result<TimerHandle,TransactionError> {
+        self.schedule(Instant::now()+delay, None, event)
+    }
+
+    ///Событие будет срабатывать каждые interval, начиная через interval
+    pub fn schedule_every(&self, interval:Duration, event:TimerEvent) -> Result<TimerHandle,TransactionError> {
+        self.schedule(Instant::now()+interval, Some(interval), event)
+    }
+
+    ///Отменяет событие, если оно уже сработало(и не повторяется), то ничего не происходит
+    pub fn cancel(&self, handle:TimerHandle) -> Result<(),TransactionError> {
+        mutex_lock!(&self.timer_sender => timer_sender,TransactionError);
+        channel_send!(timer_sender, TimerCommand::Cancel(handle), TransactionError);
+
+        ok!()
+    }
+
+    fn schedule(&self, at:Instant, interval:Option<Duration>, event:TimerEvent) -> Result<TimerHandle,TransactionError> {
+        let handle=TimerHandle(self.next_handle.fetch_add(1, Ordering::SeqCst));
+
+        mutex_lock!(&self.timer_sender => timer_sender,TransactionError);
+        channel_send!(timer_sender, TimerCommand::Schedule(handle, at, interval, event), TransactionError);
+
+        ok!(handle)
+    }
+
+    fn lifecycle(timer_receiver:mpsc::Receiver<TimerCommand>, handler_sender:HandlerSender) {
+        let mut events:BTreeMap<(Instant,TimerHandle),(Option<Duration>,TimerEvent)>=BTreeMap::new();
+
+        loop {
+            let now=Instant::now();
+
+            //fire all expired events
+            loop {
+                let key=match events.keys().next() {
+                    Some(&key) if key.0 <= now => key,
+                    _ => break,
+                };
+
+                let (interval,event)=events.remove(&key).unwrap();
+
+                if handler_sender.send(HandlerCommand::Timer(key.1, event.clone())).is_err() {
+                    return;
+                }
+
+                match interval {
+                    Some(interval) => {
+                        //missed periods are skipped
+                        let mut at=key.0+interval;
+
+                        if at <= now {
+                            at=now+interval;
+                        }
+
+                        events.insert((at,key.1), (Some(interval),event));
+                    },
+                    None => {},
+                }
+            }
+
+            let command=match events.keys().next() {
+                Some(&(at,_)) => {
+                    match timer_receiver.recv_timeout(at-now) {
+                        Ok(command) => command,
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                },
+                None => {
+                    match timer_receiver.recv() {
+                        Ok(command) => command,
+                        Err(_) => return,
+                    }
+                }
+            };
+
+            match command {
+                TimerCommand::Schedule(handle, at, interval, event) => {
+                    events.insert((at,handle), (interval,event));
+                },
+                TimerCommand::Cancel(handle) => {
+                    let key=events.keys().find(|&&(_,event_handle)| event_handle==handle).cloned();
+
+                    match key {
+                        Some(key) => {events.remove(&key);},
+                        None => {},
+                    }
+                },
+                TimerCommand::Shutdown => return,
+            }
+        }
+    }
+}
+
+impl Drop for Timer {
+    ///Останавливает поток таймера и дожидается его завершения
+    fn drop(&mut self) {
+        match self.timer_sender.lock() {
+            Ok(timer_sender) => {
+                if let Err(_) = timer_sender.send(TimerCommand::Shutdown) {
+                    warn!("Timer thread has already finished");
+                }
+            },
+            Err(_) => {
+                warn!("Timer is poisoned, its thread is not stopped");
+                return;
+            },
+        }
+
+        match self.join_handle.lock() {
+            Ok(mut join_handle) => {
+                match join_handle.take() {
+                    Some(join_handle) => {
+                        if let Err(_) = join_handle.join() {
+                            error!("Timer thread has panicked");
+                        }
+                    },
+                    None => {},
+                }
+            },
+            Err(_) => {},
+        }
+    }
+}
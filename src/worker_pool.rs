@@ -0,0 +1,139 @@
+//!Пул рабочих потоков, выполняющих задачи из TasksQueue.
+//!Рабочие потоки не обращаются к Sender и Автомату, результат выполнения задачи
+//!отправляется потоку Handler командой HandlerCommand::TaskCompleted, а сигнал завершения графа задач -
+//!командой HandlerCommand::AutomatSignal.
+
+use std;
+
+use std::thread::JoinHandle;
+
+use handler::{HandlerSender,HandlerCommand};
+
+use ::ArcTasksQueue;
+
+///Пул рабочих потоков
+pub struct WorkerPool {
+    tasks_queue:ArcTasksQueue,
+    workers:Vec<JoinHandle<()>>,
+}
+
+///Рабочий поток
+struct Worker {
+    index:usize,
+    tasks_queue:ArcTasksQueue,
+    handler_sender:HandlerSender,
+}
+
+///Сообщает Handler-у о панике рабочего потока, паника не проходит через lifecycle, поэтому о ней
+///сообщается при раскрутке стека
+struct PanicGuard {
+    index:usize,
+    handler_sender:HandlerSender,
+}
+
+impl WorkerPool {
+    ///Запускает count рабочих потоков, если count равен 0, то задачи выполняет поток Handler
+    pub fn start(count:usize, tasks_queue:ArcTasksQueue, handler_sender:HandlerSender) -> Self {
+        let workers=(0..count).map(|index| {
+            let worker=Worker{
+                index,
+                tasks_queue:tasks_queue.clone(),
+                handler_sender:handler_sender.clone(),
+            };
+
+            std::thread::Builder::new().name(format!("Handler.Worker#{}",index)).spawn(move|| {
+                let _panic_guard=PanicGuard{
+                    index:worker.index,
+                    handler_sender:worker.handler_sender.clone(),
+                };
+
+                worker.lifecycle();
+            }).unwrap()
+        }).collect();
+
+        WorkerPool {
+            tasks_queue,
+            workers,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+}
+
+impl Drop for WorkerPool {
+    ///Закрывает очередь и дожидается завершения рабочих потоков
+    fn drop(&mut self) {
+        if self.tasks_queue.close().is_err() {
+            error!("Can not close TasksQueue, workers are left running");
+            return;
+        }
+
+        for (index, worker) in self.workers.drain(..).enumerate() {
+            if worker.join().is_err() {
+                error!("Worker #{} has panicked", index);
+            }
+        }
+    }
+}
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            error!("Worker #{} has panicked", self.index);
+
+            try_send![self.handler_sender, HandlerCommand::WorkerThreadCrash(self.index)];
+        }
+    }
+}
+
+impl Worker {
+    fn lifecycle(&self) {
+        loop {
+            let entry=match self.tasks_queue.wait_pop(self.index) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return,
+                Err(error) => {
+                    error!("Worker #{} Error: {}", self.index, error);
+
+                    try_send![self.handler_sender, HandlerCommand::WorkerThreadCrash(self.index)];
+
+                    return;
+                }
+            };
+
+            let receipt=entry.receipt();
+            let result=entry.execute();
+            let succeeded=result.is_ok();
+
+            if self.handler_sender.send(HandlerCommand::TaskCompleted(result)).is_err() {
+                return;
+            }
+
+            let signal=match self.tasks_queue.finish(receipt, succeeded) {
+                Ok(signal) => signal,
+                Err(error) => {
+                    error!("Worker #{} Error: {}", self.index, error);
+
+                    try_send![self.handler_sender, HandlerCommand::WorkerThreadCrash(self.index)];
+
+                    return;
+                }
+            };
+
+            match signal {
+                Some(signal) => {
+                    if self.handler_sender.send(HandlerCommand::AutomatSignal(signal)).is_err() {
+                        return;
+                    }
+                },
+                None => {},
+            }
+        }
+    }
+}